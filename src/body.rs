@@ -0,0 +1,165 @@
+//!
+//! A module that provides body type for requests and responses
+//!
+
+use crate::route::Error;
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use sync_wrapper::SyncWrapper;
+
+enum Kind {
+    Full(Option<Bytes>),
+    Stream(SyncWrapper<UnsyncBoxBody<Bytes, Box<Error>>>),
+}
+
+///
+/// A body of requests and responses
+///
+/// Body is either buffered in memory or streamed.
+/// Buffered bodies can be inspected with [Body::as_bytes] before they're sent,
+/// which enables features like compression and ETag to handle them efficiently.
+///
+pub struct Body {
+    kind: Kind,
+}
+
+impl Body {
+    ///
+    /// Create new empty body
+    ///
+    pub fn empty() -> Self {
+        Self::from(Bytes::new())
+    }
+
+    ///
+    /// Create new body from arbitrary body implementation
+    ///
+    pub fn new<B>(body: B) -> Self
+    where
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<Box<Error>>,
+    {
+        Self {
+            kind: Kind::Stream(SyncWrapper::new(body.map_err(Into::into).boxed_unsync())),
+        }
+    }
+
+    ///
+    /// Create new streamed body from stream of chunks
+    ///
+    pub fn from_stream<S, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<Box<Error>>,
+    {
+        Self::new(StreamBody::new(stream.map_ok(Frame::data)))
+    }
+
+    ///
+    /// Get content of body if it's buffered in memory
+    ///
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match &self.kind {
+            Kind::Full(bytes) => bytes.as_ref(),
+            Kind::Stream(_) => None,
+        }
+    }
+
+    ///
+    /// Read whole body into memory
+    ///
+    pub async fn to_bytes(self) -> Result<Bytes, Box<Error>> {
+        match self.kind {
+            Kind::Full(bytes) => Ok(bytes.unwrap_or_default()),
+            Kind::Stream(stream) => Ok(stream.into_inner().collect().await?.to_bytes()),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl HttpBody for Body {
+    type Data = Bytes;
+    type Error = Box<Error>;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match &mut self.get_mut().kind {
+            Kind::Full(bytes) => Poll::Ready(
+                bytes
+                    .take()
+                    .filter(|bytes| !bytes.is_empty())
+                    .map(|bytes| Ok(Frame::data(bytes))),
+            ),
+            Kind::Stream(stream) => Pin::new(stream.get_mut()).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.kind {
+            Kind::Full(bytes) => bytes.as_ref().is_none_or(Bytes::is_empty),
+            Kind::Stream(_) => false,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.kind {
+            Kind::Full(bytes) => SizeHint::with_exact(bytes.as_ref().map_or(0, Bytes::len) as u64),
+            Kind::Stream(_) => SizeHint::default(),
+        }
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(value: Bytes) -> Self {
+        Self {
+            kind: Kind::Full(Some(value)),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Self::from(Bytes::from(value))
+    }
+}
+
+impl From<String> for Body {
+    fn from(value: String) -> Self {
+        Self::from(Bytes::from(value))
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(value: &'static str) -> Self {
+        Self::from(Bytes::from_static(value.as_bytes()))
+    }
+}
+
+impl From<&'static [u8]> for Body {
+    fn from(value: &'static [u8]) -> Self {
+        Self::from(Bytes::from_static(value))
+    }
+}
+
+impl From<Full<Bytes>> for Body {
+    fn from(value: Full<Bytes>) -> Self {
+        // Full yields its only frame immediately
+        let mut value = value;
+        let mut cx = Context::from_waker(Waker::noop());
+        match HttpBody::poll_frame(Pin::new(&mut value), &mut cx) {
+            Poll::Ready(Some(Ok(frame))) => Self::from(frame.into_data().unwrap_or_default()),
+            _ => Self::empty(),
+        }
+    }
+}
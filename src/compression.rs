@@ -0,0 +1,444 @@
+//!
+//! A module that compresses responses negotiated via `Accept-Encoding`
+//!
+//! Assign [Compression] to [crate::response::ResponseConfig::compression] to
+//! enable it for an application or a route subtree. Routes can opt out
+//! for a single response by inserting [NoCompression] into its extensions.
+//!
+
+use crate::body::Body;
+use crate::response::append_vary;
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder,
+};
+use async_compression::Level;
+use futures_util::TryStreamExt;
+use http_body_util::BodyExt;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG,
+};
+use hyper::{HeaderMap, Response, StatusCode};
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+///
+/// A marker that disables compression of response
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::body::Body;
+/// use humus_terra::compression::NoCompression;
+/// use hyper::Response;
+///
+/// let mut response = Response::new(Body::from("already small"));
+/// response.extensions_mut().insert(NoCompression);
+/// ```
+///
+#[derive(Clone, Copy, Debug)]
+pub struct NoCompression;
+
+///
+/// Content-codings supported for compression
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// [Brotli](https://www.rfc-editor.org/rfc/rfc7932)
+    Brotli,
+    /// [Zstandard](https://www.rfc-editor.org/rfc/rfc8878)
+    Zstd,
+    /// [gzip](https://www.rfc-editor.org/rfc/rfc1952)
+    Gzip,
+}
+
+impl Encoding {
+    ///
+    /// Get name of content-coding
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    ///
+    /// Get content-coding of given name
+    ///
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]
+            .into_iter()
+            .find(|encoding| encoding.as_str().eq_ignore_ascii_case(name))
+            .or_else(|| {
+                name.eq_ignore_ascii_case("x-gzip")
+                    .then_some(Encoding::Gzip)
+            })
+    }
+
+    pub(crate) fn encode<R>(&self, reader: R) -> Box<dyn AsyncRead + Send + Unpin>
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        match self {
+            // Default quality of brotli (11) is too slow for on-the-fly compression
+            Encoding::Brotli => Box::new(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+            Encoding::Zstd => Box::new(ZstdEncoder::new(reader)),
+            Encoding::Gzip => Box::new(GzipEncoder::new(reader)),
+        }
+    }
+
+    pub(crate) fn decode<R>(&self, reader: R) -> Box<dyn AsyncRead + Send + Unpin>
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        match self {
+            Encoding::Brotli => Box::new(BrotliDecoder::new(reader)),
+            Encoding::Zstd => Box::new(ZstdDecoder::new(reader)),
+            Encoding::Gzip => Box::new(GzipDecoder::new(reader)),
+        }
+    }
+}
+
+///
+/// A configuration of response compression
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::compression::{Compression, Encoding};
+///
+/// let compression = Compression::new()
+///     .encodings(&[Encoding::Zstd, Encoding::Gzip])
+///     .min_size(512)
+///     .allow_type("application/wasm");
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    allow_types: Vec<String>,
+    deny_types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    ///
+    /// Create new configuration with default options
+    ///
+    /// - Encodings in order of preference: `br`, `zstd`, `gzip`
+    /// - Minimum size: 1024 bytes
+    /// - Allowed types: `text/*`, JSON, JavaScript, XML, SVG and WebAssembly
+    /// - Denied types: `text/event-stream`
+    ///
+    pub fn new() -> Self {
+        Self {
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+            min_size: 1024,
+            allow_types: [
+                "text/*",
+                "application/json",
+                "application/*+json",
+                "application/javascript",
+                "application/xml",
+                "application/*+xml",
+                "image/svg+xml",
+                "application/wasm",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            deny_types: vec!["text/event-stream".to_string()],
+        }
+    }
+
+    ///
+    /// Set encodings to use, in order of preference
+    ///
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    ///
+    /// Set minimum size of responses to compress
+    ///
+    /// Streamed responses of unknown size are always compressed.
+    ///
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    ///
+    /// Allow compression of given media type (e.g. `text/*`, `application/*+json`)
+    ///
+    pub fn allow_type(mut self, media_type: &str) -> Self {
+        self.allow_types.push(media_type.to_ascii_lowercase());
+        self
+    }
+
+    ///
+    /// Deny compression of given media type; This takes precedence over allowed types
+    ///
+    pub fn deny_type(mut self, media_type: &str) -> Self {
+        self.deny_types.push(media_type.to_ascii_lowercase());
+        self
+    }
+
+    fn is_compressible(&self, headers: &HeaderMap) -> bool {
+        let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            None => return false,
+            Some(content_type) => content_type,
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        let matches = |pattern: &String| media_type_matches(pattern, &media_type);
+        !self.deny_types.iter().any(matches) && self.allow_types.iter().any(matches)
+    }
+
+    ///
+    /// Choose encoding for request with given `Accept-Encoding` header
+    ///
+    pub fn negotiate(&self, accept_encoding: Option<&HeaderValue>) -> Option<Encoding> {
+        let accept_encoding = accept_encoding?.to_str().ok()?;
+        let accepted = parse_accept_encoding(accept_encoding);
+
+        let quality = |name: &str| {
+            accepted
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+                .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+                .map_or(0.0, |(_, quality)| *quality)
+        };
+
+        let mut chosen: Option<(Encoding, f32)> = None;
+        for encoding in &self.encodings {
+            let quality = quality(encoding.as_str());
+            if quality > 0.0 && chosen.is_none_or(|(_, best)| quality > best) {
+                chosen = Some((*encoding, quality));
+            }
+        }
+
+        chosen.map(|(encoding, _)| encoding)
+    }
+
+    pub(crate) async fn apply(
+        &self,
+        accept_encoding: Option<&HeaderValue>,
+        is_head: bool,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let (mut parts, body) = response.into_parts();
+
+        if parts.extensions.get::<NoCompression>().is_some()
+            || !self.is_compressible(&parts.headers)
+        {
+            return Response::from_parts(parts, body);
+        }
+
+        // Representation differs by Accept-Encoding from now on
+        append_vary(&mut parts.headers, ACCEPT_ENCODING);
+
+        let no_transform = parts
+            .headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+
+        let size = body.as_bytes().map(|bytes| bytes.len() as u64).or_else(|| {
+            parts
+                .headers
+                .get(CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok())
+                .and_then(|length| length.parse().ok())
+        });
+
+        if is_head
+            || no_transform
+            || parts.status.is_informational()
+            || parts.status == StatusCode::NO_CONTENT
+            || parts.status == StatusCode::NOT_MODIFIED
+            || parts.status == StatusCode::PARTIAL_CONTENT
+            || parts.headers.contains_key(CONTENT_ENCODING)
+            || parts.headers.contains_key(CONTENT_RANGE)
+            || size.is_some_and(|size| size < self.min_size)
+        {
+            return Response::from_parts(parts, body);
+        }
+
+        let encoding = match self.negotiate(accept_encoding) {
+            None => return Response::from_parts(parts, body),
+            Some(encoding) => encoding,
+        };
+
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        parts.headers.remove(CONTENT_LENGTH);
+        // Byte ranges of encoded representation are not supported
+        parts.headers.remove(ACCEPT_RANGES);
+
+        // Encoded representation is not byte-identical to the original one
+        if let Some(etag) = parts.headers.get(ETAG).and_then(|etag| etag.to_str().ok()) {
+            if !etag.starts_with("W/") {
+                if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    parts.headers.insert(ETAG, weak);
+                }
+            }
+        }
+
+        let body = match body.as_bytes() {
+            Some(bytes) => {
+                let mut encoded = vec![];
+                let mut encoder = encoding.encode(io::Cursor::new(bytes.clone()));
+                match encoder.read_to_end(&mut encoded).await {
+                    Ok(_) => {
+                        parts.headers.insert(CONTENT_LENGTH, encoded.len().into());
+                        Body::from(encoded)
+                    }
+                    Err(_) => {
+                        parts.headers.remove(CONTENT_ENCODING);
+                        parts.headers.insert(CONTENT_LENGTH, bytes.len().into());
+                        body
+                    }
+                }
+            }
+            None => {
+                let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+                Body::from_stream(ReaderStream::new(encoding.encode(reader)))
+            }
+        };
+
+        Response::from_parts(parts, body)
+    }
+}
+
+fn media_type_matches(pattern: &str, media_type: &str) -> bool {
+    let (pattern_type, pattern_subtype) = pattern.split_once('/').unwrap_or((pattern, ""));
+    let (type_, subtype) = media_type.split_once('/').unwrap_or((media_type, ""));
+
+    if pattern_type != "*" && pattern_type != type_ {
+        return false;
+    }
+
+    match pattern_subtype.strip_prefix('*') {
+        None => pattern_subtype == subtype,
+        // `*`, or suffix like `*+json`
+        Some(suffix) => subtype.ends_with(suffix),
+    }
+}
+
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+
+            let mut quality = 1.0;
+            for param in params {
+                if let Some(q) = param.trim().strip_prefix("q=") {
+                    quality = q.trim().parse::<f32>().ok()?.clamp(0.0, 1.0);
+                }
+            }
+
+            Some((coding.to_ascii_lowercase(), quality))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::VARY;
+
+    #[test]
+    fn negotiate() {
+        let compression = Compression::new();
+        let negotiate =
+            |value: &'static str| compression.negotiate(Some(&HeaderValue::from_static(value)));
+
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.1, gzip;q=0"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("br;q=0, zstd;q=0, gzip;q=0"), None);
+        assert_eq!(compression.negotiate(None), None);
+    }
+
+    #[test]
+    fn media_types() {
+        assert!(media_type_matches("text/*", "text/html"));
+        assert!(media_type_matches(
+            "application/*+json",
+            "application/ld+json"
+        ));
+        assert!(!media_type_matches("application/json", "application/jsonp"));
+        assert!(!media_type_matches("text/*", "image/png"));
+    }
+
+    #[tokio::test]
+    async fn compress_full() {
+        let content = "{\"key\": \"value\"}".repeat(100);
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(content.clone()))
+            .unwrap();
+
+        let response = Compression::new()
+            .apply(Some(&HeaderValue::from_static("gzip")), false, response)
+            .await;
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+
+        let encoded = response.into_body().to_bytes().await.unwrap();
+        let mut decoded = String::new();
+        GzipDecoder::new(encoded.as_ref())
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[tokio::test]
+    async fn skip_small_and_opted_out() {
+        let accept = HeaderValue::from_static("gzip");
+
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("small"))
+            .unwrap();
+        let response = Compression::new()
+            .apply(Some(&accept), false, response)
+            .await;
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+
+        let mut response = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("large".repeat(1000)))
+            .unwrap();
+        response.extensions_mut().insert(NoCompression);
+        let response = Compression::new()
+            .apply(Some(&accept), false, response)
+            .await;
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,278 @@
+//!
+//! A module that evaluates conditional requests ([RFC 9110](https://www.rfc-editor.org/rfc/rfc9110#section-13))
+//!
+//! Assign [AutoETag] to [crate::response::ResponseConfig::auto_etag] to tag
+//! buffered responses with `ETag` computed from their content, and answer
+//! `GET` and `HEAD` requests with `304 Not Modified` if client has them already.
+//!
+//! Preconditions of state-changing requests like `PUT` and `DELETE` must be
+//! evaluated before the change happens, so routes check them with [check].
+//!
+
+use crate::body::Body;
+use crate::encrypt::Sha256;
+use crate::response::ResponseBuilder;
+use base64::Engine;
+use headers::{
+    ETag, HeaderMapExt, IfMatch, IfModifiedSince, IfNoneMatch, IfUnmodifiedSince, LastModified,
+};
+use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use std::time::SystemTime;
+
+///
+/// A configuration of automatic `ETag`
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::conditional::AutoETag;
+/// use humus_terra::response::ResponseConfig;
+///
+/// let mut config = ResponseConfig::new();
+/// config.auto_etag = Some(AutoETag::new().weak(true));
+/// ```
+///
+#[derive(Clone, Debug, Default)]
+pub struct AutoETag {
+    weak: bool,
+}
+
+impl AutoETag {
+    ///
+    /// Create new configuration that computes strong `ETag`
+    ///
+    pub fn new() -> Self {
+        Self { weak: false }
+    }
+
+    ///
+    /// Set whether to compute weak `ETag`; `W/"..."`.
+    ///
+    /// Weak tags only promise semantic equivalence, so they don't validate byte ranges.
+    ///
+    pub fn weak(mut self, weak: bool) -> Self {
+        self.weak = weak;
+        self
+    }
+
+    fn compute(&self, content: &[u8]) -> ETag {
+        let digest = Sha256::digest(content);
+        let tag = base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(&digest[..16]);
+
+        let etag = match self.weak {
+            true => format!("W/\"{}\"", tag),
+            false => format!("\"{}\"", tag),
+        };
+        etag.parse().unwrap()
+    }
+
+    ///
+    /// Tag response and answer it with `304 Not Modified` if request is fulfilled already.
+    ///
+    /// Only successful responses to `GET` and `HEAD` are considered;
+    /// Responses tagged by routes keep their own `ETag`.
+    ///
+    pub(crate) fn apply(
+        &self,
+        method: &Method,
+        headers: &HeaderMap,
+        mut response: Response<Body>,
+    ) -> Response<Body> {
+        if (method != Method::GET && method != Method::HEAD) || response.status() != StatusCode::OK
+        {
+            return response;
+        }
+
+        let etag = match response.headers().typed_get::<ETag>() {
+            Some(etag) => etag,
+            None => match response.body().as_bytes() {
+                None => return response,
+                Some(content) => {
+                    let etag = self.compute(content);
+                    response.headers_mut().typed_insert(etag.clone());
+                    etag
+                }
+            },
+        };
+
+        let last_modified = response
+            .headers()
+            .typed_get::<LastModified>()
+            .map(SystemTime::from);
+        match evaluate(method, headers, Some(&etag), last_modified) {
+            None => response,
+            Some(status) => {
+                let (mut parts, _) = response.into_parts();
+                parts.status = status;
+                for name in [CONTENT_LENGTH, CONTENT_TYPE, CONTENT_RANGE] {
+                    parts.headers.remove(name);
+                }
+                Response::from_parts(parts, Body::empty())
+            }
+        }
+    }
+}
+
+///
+/// Check preconditions of request against current representation of resource.
+///
+/// `etag` is value of `ETag` header like `"v1"`, and `last_modified` is
+/// when the representation was modified last. Pass `None` to both if the
+/// resource doesn't exist.
+///
+/// Returns response to answer with instead of processing the request;
+/// `304 Not Modified` or `412 Precondition Failed`.
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::body::Body;
+/// use humus_terra::conditional::check;
+/// use hyper::{Request, Response};
+///
+/// fn delete(request: Request<Body>, version: &str) -> Response<Body> {
+///     if let Some(response) = check(&request, Some(version), None) {
+///         return response;
+///     }
+///
+///     // Delete resource
+///     Response::new(Body::empty())
+/// }
+/// ```
+///
+pub fn check<B>(
+    request: &Request<B>,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Option<Response<Body>> {
+    let etag = etag.and_then(|etag| etag.parse::<ETag>().ok());
+    let status = evaluate(
+        request.method(),
+        request.headers(),
+        etag.as_ref(),
+        last_modified,
+    )?;
+
+    let mut builder = ResponseBuilder::from_request(request).status(status);
+    if let (Some(etag), Some(headers)) = (etag, builder.headers_mut()) {
+        headers.typed_insert(etag);
+    }
+
+    Some(builder.body(Body::empty()).unwrap())
+}
+
+///
+/// Evaluate preconditions of request against current representation
+///
+/// `etag` is `None` if there's no current representation.
+/// Returns status code to respond with instead of processing the request;
+/// `304 Not Modified` or `412 Precondition Failed`.
+///
+pub(crate) fn evaluate(
+    method: &Method,
+    headers: &HeaderMap,
+    etag: Option<&ETag>,
+    last_modified: Option<SystemTime>,
+) -> Option<StatusCode> {
+    if let Some(if_match) = headers.typed_get::<IfMatch>() {
+        let passes = etag.is_some_and(|etag| if_match.precondition_passes(etag));
+        if !passes {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(if_unmodified_since) = headers.typed_get::<IfUnmodifiedSince>() {
+        let passes =
+            last_modified.is_some_and(|time| if_unmodified_since.precondition_passes(time));
+        if !passes {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    let is_safe = method == Method::GET || method == Method::HEAD;
+
+    if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+        let passes = etag.is_none_or(|etag| if_none_match.precondition_passes(etag));
+        if !passes {
+            return Some(if is_safe {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::PRECONDITION_FAILED
+            });
+        }
+    } else if is_safe {
+        if let (Some(if_modified_since), Some(time)) =
+            (headers.typed_get::<IfModifiedSince>(), last_modified)
+        {
+            if !if_modified_since.is_modified(time) {
+                return Some(StatusCode::NOT_MODIFIED);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderValue, ETAG, IF_MATCH, IF_NONE_MATCH};
+
+    fn headers(name: hyper::header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn auto_etag() {
+        let auto_etag = AutoETag::new();
+        let response = || Response::new(Body::from("hello, world"));
+
+        let tagged = auto_etag.apply(&Method::GET, &HeaderMap::new(), response());
+        assert_eq!(tagged.status(), StatusCode::OK);
+        let etag = tagged.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with('"'));
+
+        let cached = auto_etag.apply(&Method::GET, &headers(IF_NONE_MATCH, &etag), response());
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[ETAG], etag.as_str());
+        assert!(!cached.headers().contains_key(CONTENT_TYPE));
+
+        let weak = AutoETag::new().weak(true);
+        let tagged = weak.apply(&Method::GET, &headers(IF_NONE_MATCH, &etag), response());
+        assert_eq!(tagged.status(), StatusCode::NOT_MODIFIED);
+        assert!(tagged.headers()[ETAG].to_str().unwrap().starts_with("W/"));
+
+        let posted = auto_etag.apply(&Method::POST, &HeaderMap::new(), response());
+        assert!(!posted.headers().contains_key(ETAG));
+    }
+
+    #[test]
+    fn preconditions() {
+        let request = |method: Method, name, value: &str| {
+            Request::builder()
+                .method(method)
+                .header(name, value)
+                .body(())
+                .unwrap()
+        };
+
+        let put = request(Method::PUT, IF_MATCH, "\"v1\"");
+        assert!(check(&put, Some("\"v1\""), None).is_none());
+        let failed = check(&put, Some("\"v2\""), None).unwrap();
+        assert_eq!(failed.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(failed.headers()[ETAG], "\"v2\"");
+
+        // Weak tags never match If-Match
+        assert!(check(&put, Some("W/\"v1\""), None).is_some());
+
+        let create = request(Method::PUT, IF_NONE_MATCH, "*");
+        assert!(check(&create, None, None).is_none());
+        let exists = check(&create, Some("\"v1\""), None).unwrap();
+        assert_eq!(exists.status(), StatusCode::PRECONDITION_FAILED);
+
+        let delete = request(Method::DELETE, IF_MATCH, "*");
+        let missing = check(&delete, None, None).unwrap();
+        assert_eq!(missing.status(), StatusCode::PRECONDITION_FAILED);
+    }
+}
@@ -0,0 +1,139 @@
+//!
+//! A module that provides information about connections which requests came from
+//!
+
+use crate::proxy_protocol::ProxyHeader;
+use hyper::{Request, Version};
+use std::net::SocketAddr;
+
+///
+/// A struct that contains information about TLS session of connection
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Server name requested by client via SNI
+    pub server_name: Option<String>,
+
+    /// Application protocol negotiated via ALPN
+    pub alpn_protocol: Option<Vec<u8>>,
+
+    /// Version of TLS protocol (e.g. `TLSv1.3`)
+    pub protocol_version: Option<String>,
+}
+
+///
+/// A struct that contains information about connection of request
+///
+/// The application attaches this to every request before it reaches
+/// [crate::route::Route::handle]. See [ConnectionInfo::from_request].
+///
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    id: u64,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    original_peer_addr: Option<SocketAddr>,
+    original_local_addr: Option<SocketAddr>,
+    version: Version,
+    tls: Option<TlsInfo>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(id: u64, peer_addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        Self {
+            id,
+            peer_addr,
+            local_addr,
+            original_peer_addr: None,
+            original_local_addr: None,
+            version: Version::default(),
+            tls: None,
+        }
+    }
+
+    pub(crate) fn with_proxy_header(mut self, header: ProxyHeader) -> Self {
+        self.original_peer_addr = header.source;
+        self.original_local_addr = header.destination;
+        self.tls = header.tls;
+        self
+    }
+
+    pub(crate) fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    ///
+    /// Retrieve connection information from request
+    ///
+    /// Returns `None` if the request didn't come through [crate::App].
+    ///
+    pub fn from_request<B>(request: &Request<B>) -> Option<&Self> {
+        request.extensions().get::<Self>()
+    }
+
+    ///
+    /// Get identifier of connection, unique within the application
+    ///
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    ///
+    /// Get address of remote peer
+    ///
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    ///
+    /// Get local address which connection was accepted on
+    ///
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    ///
+    /// Get address of original client, reported by PROXY protocol header
+    ///
+    /// Returns `None` if PROXY protocol is disabled or proxy didn't report it.
+    /// See [crate::App::with_proxy_protocol].
+    ///
+    pub fn original_peer_addr(&self) -> Option<SocketAddr> {
+        self.original_peer_addr
+    }
+
+    ///
+    /// Get original destination address, reported by PROXY protocol header
+    ///
+    /// Returns `None` if PROXY protocol is disabled or proxy didn't report it.
+    /// See [crate::App::with_proxy_protocol].
+    ///
+    pub fn original_local_addr(&self) -> Option<SocketAddr> {
+        self.original_local_addr
+    }
+
+    ///
+    /// Get address of remote client; Original one if reported, otherwise the peer
+    ///
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.original_peer_addr.unwrap_or(self.peer_addr)
+    }
+
+    ///
+    /// Get HTTP version of connection
+    ///
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    ///
+    /// Get TLS information of connection, if connection is secured
+    ///
+    /// For connections through PROXY protocol, this describes TLS session
+    /// terminated by the proxy, if reported.
+    ///
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+}
@@ -0,0 +1,477 @@
+//!
+//! A module that implements [CORS](https://fetch.spec.whatwg.org/#http-cors-protocol)
+//!
+//! Assign [Cors] to [crate::response::ResponseConfig::cors] to enable it for
+//! an application or a route subtree. Preflight requests are answered
+//! before they reach routes; Other responses are stamped with
+//! `Access-Control-*` headers matching the origin of request.
+//!
+
+use crate::body::Body;
+use crate::response::{append_vary, ResponseBuilder, ResponseConfig};
+use hyper::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+static ACCESS_CONTROL_REQUEST_PRIVATE_NETWORK: HeaderName =
+    HeaderName::from_static("access-control-request-private-network");
+static ACCESS_CONTROL_ALLOW_PRIVATE_NETWORK: HeaderName =
+    HeaderName::from_static("access-control-allow-private-network");
+
+#[derive(Clone)]
+enum OriginRule {
+    Exact(String),
+    Subdomain { scheme: String, suffix: String },
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl OriginRule {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginRule::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+            OriginRule::Subdomain { scheme, suffix } => {
+                let host = match origin.split_once("://") {
+                    Some((s, host)) if s.eq_ignore_ascii_case(scheme) => host,
+                    _ => return false,
+                };
+
+                // Index may land inside multi-byte character of malicious origin
+                host.len() > suffix.len()
+                    && host
+                        .get(host.len() - suffix.len()..)
+                        .is_some_and(|tail| tail.eq_ignore_ascii_case(suffix))
+            }
+            OriginRule::Predicate(predicate) => predicate(origin),
+        }
+    }
+}
+
+impl Debug for OriginRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OriginRule::Exact(exact) => write!(f, "{}", exact),
+            OriginRule::Subdomain { scheme, suffix } => write!(f, "{}://*{}", scheme, suffix),
+            OriginRule::Predicate(_) => write!(f, "<predicate>"),
+        }
+    }
+}
+
+///
+/// A configuration of CORS for application or route subtree
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use hyper::Method;
+/// use humus_terra::cors::Cors;
+///
+/// let cors = Cors::new()
+///     .allow_origin("https://example.com")
+///     .allow_origin("https://*.example.com")
+///     .allow_methods(&[Method::GET, Method::POST])
+///     .allow_credentials(true)
+///     .max_age(Duration::from_secs(600));
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct Cors {
+    any_origin: bool,
+    origins: Vec<OriginRule>,
+    methods: Vec<Method>,
+    headers: Option<Vec<HeaderName>>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+    private_network: bool,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cors {
+    ///
+    /// Create new configuration that allows no origin
+    ///
+    /// Methods `GET`, `HEAD`, `POST`, `PUT`, `PATCH` and `DELETE` are allowed, and
+    /// every header requested by preflight is allowed by default.
+    ///
+    pub fn new() -> Self {
+        Self {
+            any_origin: false,
+            origins: vec![],
+            methods: vec![
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ],
+            headers: None,
+            expose_headers: vec![],
+            credentials: false,
+            max_age: None,
+            private_network: false,
+        }
+    }
+
+    ///
+    /// Allow every origin
+    ///
+    pub fn allow_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self
+    }
+
+    ///
+    /// Allow given origin
+    ///
+    /// Origin can be exact (`https://example.com`) or
+    /// can match every subdomain (`https://*.example.com`).
+    ///
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        let rule = match origin.split_once("://*.") {
+            None => OriginRule::Exact(origin.to_string()),
+            Some((scheme, domain)) => OriginRule::Subdomain {
+                scheme: scheme.to_string(),
+                suffix: format!(".{}", domain),
+            },
+        };
+
+        self.origins.push(rule);
+        self
+    }
+
+    ///
+    /// Allow origins for which given predicate returns `true`
+    ///
+    pub fn allow_origin_fn<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origins
+            .push(OriginRule::Predicate(Arc::new(predicate)));
+        self
+    }
+
+    ///
+    /// Set methods allowed for cross-origin requests
+    ///
+    pub fn allow_methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    ///
+    /// Set request headers allowed for cross-origin requests
+    ///
+    /// Without this, every header requested by preflight is allowed.
+    ///
+    pub fn allow_headers(mut self, headers: &[HeaderName]) -> Self {
+        self.headers = Some(headers.to_vec());
+        self
+    }
+
+    ///
+    /// Set response headers exposed to scripts of allowed origins
+    ///
+    pub fn expose_headers(mut self, headers: &[HeaderName]) -> Self {
+        self.expose_headers = headers.to_vec();
+        self
+    }
+
+    ///
+    /// Allow cross-origin requests to include credentials (cookies, authorization)
+    ///
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.credentials = allow;
+        self
+    }
+
+    ///
+    /// Set how long preflight results can be cached
+    ///
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    ///
+    /// Allow requests from public networks to this server in private network
+    ///
+    /// See [Private Network Access](https://wicg.github.io/private-network-access/).
+    ///
+    pub fn allow_private_network(mut self, allow: bool) -> Self {
+        self.private_network = allow;
+        self
+    }
+
+    ///
+    /// Check if given origin is allowed
+    ///
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|rule| rule.matches(origin))
+    }
+
+    pub(crate) fn is_preflight<B>(request: &Request<B>) -> bool {
+        request.method() == Method::OPTIONS
+            && request.headers().contains_key(ORIGIN)
+            && request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    // Wildcard is not permitted for credentialed requests
+    fn is_wildcard(&self) -> bool {
+        self.any_origin && !self.credentials
+    }
+
+    fn allow_origin_value(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if !self.is_allowed(origin.to_str().ok()?) {
+            return None;
+        }
+
+        if self.is_wildcard() {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            Some(origin.clone())
+        }
+    }
+
+    pub(crate) fn preflight<B>(
+        &self,
+        request: &Request<B>,
+        config: &ResponseConfig,
+    ) -> Response<Body> {
+        let mut response = ResponseBuilder::with_config(config)
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+
+        let headers = response.headers_mut();
+        append_vary(headers, ORIGIN);
+        append_vary(headers, ACCESS_CONTROL_REQUEST_METHOD);
+        append_vary(headers, ACCESS_CONTROL_REQUEST_HEADERS);
+
+        let origin = match request
+            .headers()
+            .get(ORIGIN)
+            .and_then(|origin| self.allow_origin_value(origin))
+        {
+            None => return response,
+            Some(origin) => origin,
+        };
+
+        let method_allowed = request
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            .is_some_and(|method| self.methods.contains(&method));
+        if !method_allowed {
+            return response;
+        }
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+
+        let methods = join(self.methods.iter().map(Method::as_str));
+        if let Ok(methods) = HeaderValue::from_str(&methods) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+
+        let allow_headers = match &self.headers {
+            Some(allowed) => {
+                HeaderValue::from_str(&join(allowed.iter().map(HeaderName::as_str))).ok()
+            }
+            None => request
+                .headers()
+                .get(ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned(),
+        };
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+
+        let private_network = request
+            .headers()
+            .get(&ACCESS_CONTROL_REQUEST_PRIVATE_NETWORK)
+            .is_some_and(|value| value == "true");
+        if self.private_network && private_network {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_PRIVATE_NETWORK.clone(),
+                HeaderValue::from_static("true"),
+            );
+        }
+
+        response
+    }
+
+    pub(crate) fn apply<B>(&self, origin: Option<&HeaderValue>, response: &mut Response<B>) {
+        let headers = response.headers_mut();
+        if !self.is_wildcard() {
+            append_vary(headers, ORIGIN);
+        }
+
+        // Origin is decided here only, even if route set these by itself
+        headers.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+        headers.remove(ACCESS_CONTROL_ALLOW_CREDENTIALS);
+
+        let origin = match origin.and_then(|origin| self.allow_origin_value(origin)) {
+            None => return,
+            Some(origin) => origin,
+        };
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if !self.expose_headers.is_empty() {
+            let expose = join(self.expose_headers.iter().map(HeaderName::as_str));
+            if let Ok(expose) = HeaderValue::from_str(&expose) {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose);
+            }
+        }
+    }
+}
+
+fn join<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    items.collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::VARY;
+
+    fn preflight(cors: &Cors, origin: &str, method: &str) -> Response<Body> {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(())
+            .unwrap();
+
+        assert!(Cors::is_preflight(&request));
+        cors.preflight(&request, &ResponseConfig::new())
+    }
+
+    #[test]
+    fn origin_rules() {
+        let cors = Cors::new()
+            .allow_origin("https://example.com")
+            .allow_origin("https://*.example.org")
+            .allow_origin_fn(|origin| origin.ends_with(":8080"));
+
+        assert!(cors.is_allowed("https://example.com"));
+        assert!(!cors.is_allowed("https://a.example.com"));
+        assert!(cors.is_allowed("https://a.b.example.org"));
+        assert!(!cors.is_allowed("https://example.org"));
+        assert!(!cors.is_allowed("http://a.example.org"));
+        assert!(!cors.is_allowed("https://evilexample.org"));
+        assert!(cors.is_allowed("http://localhost:8080"));
+
+        let cors = Cors::new().allow_origin("https://*.x");
+        assert!(!cors.is_allowed("https://éx"));
+        assert!(cors.is_allowed("https://é.x"));
+    }
+
+    #[test]
+    fn preflight_allowed() {
+        let cors = Cors::new()
+            .allow_origin("https://example.com")
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(60));
+        let response = preflight(&cors, "https://example.com", "PUT");
+
+        let headers = response.headers();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "60");
+        assert!(headers[VARY].to_str().unwrap().contains("origin"));
+    }
+
+    #[test]
+    fn preflight_rejected() {
+        let cors = Cors::new().allow_origin("https://example.com");
+
+        let response = preflight(&cors, "https://example.net", "GET");
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let response = preflight(&cors, "https://example.com", "CONNECT");
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn apply_any_origin() {
+        let origin = HeaderValue::from_static("https://example.com");
+
+        let mut response = Response::new(());
+        Cors::new()
+            .allow_any_origin()
+            .apply(Some(&origin), &mut response);
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!response.headers().contains_key(VARY));
+
+        let mut response = Response::new(());
+        Cors::new()
+            .allow_any_origin()
+            .allow_credentials(true)
+            .apply(Some(&origin), &mut response);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(response.headers()[VARY], "origin");
+    }
+
+    #[test]
+    fn disallowed_origin() {
+        let mut config = ResponseConfig::new();
+        config.access_control_allow_origin = Some("*".to_string());
+        config.cors = Some(Cors::new().allow_origin("https://example.com"));
+        let cors = config.cors.as_ref().unwrap();
+
+        let origin = HeaderValue::from_static("https://evil.example");
+        let mut response = ResponseBuilder::with_config(&config).body(()).unwrap();
+        cors.apply(Some(&origin), &mut response);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let mut response = ResponseBuilder::with_config(&config).body(()).unwrap();
+        cors.apply(None, &mut response);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .header(ORIGIN, "https://evil.example")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(())
+            .unwrap();
+        let response = cors.preflight(&request, &config);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,252 @@
+//!
+//! A module that decompresses request bodies encoded with `Content-Encoding`
+//!
+//! Enable it with [crate::App::with_decompression]. Request bodies are decoded
+//! transparently before they reach routes. Requests with unsupported encodings
+//! are answered with `415 Unsupported Media Type`, ones with malformed
+//! `Content-Encoding` or corrupt bodies with `400 Bad Request`, and bodies growing beyond
+//! the size limit once decoded fail with [http_body_util::LengthLimitError],
+//! which is answered with `413 Payload Too Large`.
+//!
+
+use crate::body::Body;
+use crate::compression::Encoding;
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, Limited};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::Request;
+use std::fmt::{Display, Formatter};
+use std::io;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+///
+/// A configuration of request decompression
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::compression::Encoding;
+/// use humus_terra::decompression::Decompression;
+///
+/// let decompression = Decompression::new()
+///     .encodings(&[Encoding::Gzip, Encoding::Zstd])
+///     .max_size(16 * 1024 * 1024);
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct Decompression {
+    encodings: Vec<Encoding>,
+    max_size: usize,
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decompression {
+    ///
+    /// Create new configuration that accepts `br`, `zstd` and `gzip`,
+    /// limiting decoded bodies to 8 MiB
+    ///
+    pub fn new() -> Self {
+        Self {
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+
+    ///
+    /// Set encodings accepted for request bodies
+    ///
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    ///
+    /// Set maximum size of decoded bodies
+    ///
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub(crate) fn accept_encoding(&self) -> HeaderValue {
+        let names = self
+            .encodings
+            .iter()
+            .map(Encoding::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        HeaderValue::from_str(&names).unwrap()
+    }
+
+    ///
+    /// Replace body of request with decoded one.
+    /// Returns `Err` if request is encoded with unsupported encoding
+    ///
+    pub(crate) fn apply(&self, request: &mut Request<Body>) -> Result<(), DecodingError> {
+        let mut encodings = vec![];
+        for value in request.headers().get_all(CONTENT_ENCODING) {
+            let value = value.to_str().map_err(|_| DecodingError::Malformed)?;
+            for name in value.split(',').map(str::trim) {
+                if name.is_empty() || name.eq_ignore_ascii_case("identity") {
+                    continue;
+                }
+
+                match Encoding::from_name(name) {
+                    Some(encoding) if self.encodings.contains(&encoding) => {
+                        encodings.push(encoding)
+                    }
+                    _ => return Err(DecodingError::Unsupported(name.to_string())),
+                }
+            }
+        }
+
+        if encodings.is_empty() {
+            return Ok(());
+        }
+
+        // Encodings are listed in order they were applied
+        let mut body = std::mem::take(request.body_mut());
+        for encoding in encodings.iter().rev() {
+            let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+            let decoded = ReaderStream::new(encoding.decode(reader));
+            body = Body::from_stream(
+                decoded.map_err(|error| DecodingError::Corrupt(error.to_string())),
+            );
+        }
+
+        *request.body_mut() = Body::new(Limited::new(body, self.max_size));
+        request.headers_mut().remove(CONTENT_ENCODING);
+        request.headers_mut().remove(CONTENT_LENGTH);
+
+        Ok(())
+    }
+}
+
+///
+/// An error of request decompression
+///
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DecodingError {
+    ///
+    /// Encoding isn't known, or not accepted by configuration
+    ///
+    Unsupported(String),
+    ///
+    /// `Content-Encoding` isn't a valid string
+    ///
+    Malformed,
+    ///
+    /// Body can't be decoded with its encoding, like truncated gzip stream
+    ///
+    Corrupt(String),
+}
+
+impl Display for DecodingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodingError::Unsupported(name) => write!(f, "Unsupported encoding: {}", name),
+            DecodingError::Malformed => write!(f, "Malformed Content-Encoding"),
+            DecodingError::Corrupt(error) => write!(f, "Corrupt encoded body: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for DecodingError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipEncoder;
+    use http_body_util::LengthLimitError;
+    use tokio::io::AsyncReadExt;
+
+    async fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![];
+        GzipEncoder::new(content)
+            .read_to_end(&mut encoded)
+            .await
+            .unwrap();
+        encoded
+    }
+
+    fn encoded_request(encoding: &str, body: Vec<u8>) -> Request<Body> {
+        Request::builder()
+            .header(CONTENT_ENCODING, encoding)
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn decode_gzip() {
+        let mut request = encoded_request("gzip", gzip(b"{\"hello\": \"world\"}").await);
+        Decompression::new().apply(&mut request).unwrap();
+
+        assert!(!request.headers().contains_key(CONTENT_ENCODING));
+        assert!(!request.headers().contains_key(CONTENT_LENGTH));
+
+        let body = request.into_body().to_bytes().await.unwrap();
+        assert_eq!(body.as_ref(), b"{\"hello\": \"world\"}");
+    }
+
+    #[tokio::test]
+    async fn unsupported() {
+        let mut request = encoded_request("compress", vec![0; 8]);
+        assert_eq!(
+            Decompression::new().apply(&mut request),
+            Err(DecodingError::Unsupported("compress".to_string()))
+        );
+
+        let mut request = encoded_request("gzip", vec![0; 8]);
+        let decompression = Decompression::new().encodings(&[Encoding::Zstd]);
+        assert_eq!(
+            decompression.apply(&mut request),
+            Err(DecodingError::Unsupported("gzip".to_string()))
+        );
+
+        let mut request = Request::builder()
+            .header(
+                CONTENT_ENCODING,
+                HeaderValue::from_bytes(b"gzip\xff").unwrap(),
+            )
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            Decompression::new().apply(&mut request),
+            Err(DecodingError::Malformed)
+        );
+    }
+
+    #[tokio::test]
+    async fn bomb() {
+        let mut request = encoded_request("gzip", gzip(&vec![0; 1024 * 1024]).await);
+        Decompression::new()
+            .max_size(1024)
+            .apply(&mut request)
+            .unwrap();
+
+        let error = request.into_body().to_bytes().await.unwrap_err();
+        assert!(error.downcast_ref::<LengthLimitError>().is_some());
+    }
+
+    #[tokio::test]
+    async fn corrupt() {
+        let mut encoded = gzip(b"{\"hello\": \"world\"}").await;
+        encoded.truncate(encoded.len() / 2);
+        for body in [encoded, b"garbage".to_vec()] {
+            let mut request = encoded_request("gzip", body);
+            Decompression::new().apply(&mut request).unwrap();
+
+            let error = request.into_body().to_bytes().await.unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<DecodingError>(),
+                Some(DecodingError::Corrupt(_))
+            ));
+        }
+    }
+}
@@ -1,51 +1,140 @@
 use crate::error::Error;
-use aes_gcm::aead::{Aead, OsRng};
+use aes_gcm::aead::{Aead, OsRng, Payload};
 use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce};
 use base64::Engine;
+use hkdf::Hkdf;
 use sha3::Digest;
 
 pub(crate) struct Sha256;
 impl Sha256 {
-    pub fn digest(data: &str) -> Vec<u8> {
-        sha3::Sha3_256::digest(data.as_bytes()).to_vec()
+    pub fn digest(data: impl AsRef<[u8]>) -> Vec<u8> {
+        sha3::Sha3_256::digest(data.as_ref()).to_vec()
     }
 }
 
 static AES_NONCE_SIZE: usize = 12;
+static AES_TAG_SIZE: usize = 16;
+
+pub(crate) struct Aes {
+    cipher: Aes256Gcm,
+}
 
-pub(crate) struct Aes;
 impl Aes {
-    pub fn encrypt(plaintext: &str, key: &str) -> Result<String, Error> {
-        let key_hash = Sha256::digest(key);
-        let key = Key::<Aes256Gcm>::from_slice(&key_hash);
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+        }
+    }
 
+    // Encrypt into nonce followed by ciphertext and tag,
+    // authenticating associated data which must be same on decryption
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-
-        let cipher = Aes256Gcm::new(key);
-        let ciphertext = cipher
-            .encrypt(&nonce, plaintext.as_bytes())
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
             .map_err(Error::from)?;
 
         let mut encrypted_data: Vec<u8> = nonce.to_vec();
         encrypted_data.extend_from_slice(&ciphertext);
 
-        Ok(base64::prelude::BASE64_STANDARD.encode(&encrypted_data))
+        Ok(encrypted_data)
+    }
+
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < AES_NONCE_SIZE + AES_TAG_SIZE {
+            return Err(Error::new("Ciphertext too short"));
+        }
+
+        let (nonce_arr, ciphered_data) = data.split_at(AES_NONCE_SIZE);
+        let nonce = Nonce::from_slice(nonce_arr);
+
+        self.cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphered_data,
+                    aad,
+                },
+            )
+            .map_err(Error::from)
     }
+}
+
+static ENVELOPE_VERSION: u8 = 1;
+// Browsers limit a cookie to 4096 bytes
+static MAX_ENVELOPE_LENGTH: usize = 4096;
+
+// Versioned binary container of encrypted data:
+// version (1) | length of key ID (1) | key ID | encrypted data,
+// encoded with URL-safe Base64 without padding
+pub(crate) struct Envelope;
+impl Envelope {
+    pub fn encode(key_id: &str, encrypted: &[u8]) -> String {
+        let mut data = Vec::with_capacity(2 + key_id.len() + encrypted.len());
+        data.push(ENVELOPE_VERSION);
+        data.push(u8::try_from(key_id.len()).expect("Key ID is at most 255 bytes"));
+        data.extend_from_slice(key_id.as_bytes());
+        data.extend_from_slice(encrypted);
 
-    pub fn decrypt(data: &str, key: &str) -> Result<String, Error> {
-        let ciphertext = base64::prelude::BASE64_STANDARD
-            .decode(data.as_bytes())
+        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(data)
+    }
+
+    pub fn decode(encoded: &str) -> Result<(String, Vec<u8>), Error> {
+        if encoded.len() > MAX_ENVELOPE_LENGTH {
+            return Err(Error::new("Envelope too long"));
+        }
+
+        let data = base64::prelude::BASE64_URL_SAFE_NO_PAD
+            .decode(encoded.as_bytes())
             .map_err(Error::from)?;
 
-        let key_hash = Sha256::digest(key);
-        let key = Key::<Aes256Gcm>::from_slice(&key_hash);
+        let (&version, rest) = data.split_first().ok_or(Error::new("Envelope too short"))?;
+        if version != ENVELOPE_VERSION {
+            return Err(Error::new("Unsupported envelope version"));
+        }
 
-        let (nonce_arr, ciphered_data) = ciphertext.split_at(AES_NONCE_SIZE);
-        let nonce = Nonce::from_slice(nonce_arr);
+        let (&length, rest) = rest.split_first().ok_or(Error::new("Envelope too short"))?;
+        if rest.len() < length as usize {
+            return Err(Error::new("Envelope too short"));
+        }
+        let (key_id, encrypted) = rest.split_at(length as usize);
+        let key_id = std::str::from_utf8(key_id).map_err(Error::from)?;
 
-        let cipher = Aes256Gcm::new(key);
-        let plaintext = cipher.decrypt(nonce, ciphered_data).map_err(Error::from)?;
+        Ok((key_id.to_string(), encrypted.to_vec()))
+    }
+}
+
+pub(crate) struct Kdf;
+impl Kdf {
+    // Extract pseudorandom key from high-entropy secret with HKDF-SHA256
+    pub fn extract(secret: &[u8]) -> [u8; 32] {
+        let (prk, _) = Hkdf::<sha2::Sha256>::extract(None, secret);
+        prk.into()
+    }
+
+    // Stretch low-entropy passphrase into key with Argon2id
+    pub fn stretch(passphrase: &[u8], salt: &[u8]) -> Result<[u8; 32], Error> {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase, salt, &mut key)
+            .map_err(Error::from)?;
+        Ok(key)
+    }
 
-        String::from_utf8(plaintext).map_err(Error::from)
+    // Expand pseudorandom key into subkey for given purpose
+    pub fn expand(prk: &[u8; 32], info: &[u8]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        Hkdf::<sha2::Sha256>::from_prk(prk)
+            .expect("PRK is as long as hash")
+            .expand(info, &mut key)
+            .expect("Subkey is as long as hash");
+        key
     }
 }
@@ -0,0 +1,664 @@
+//!
+//! A module that provides [JSON Web Token](https://www.rfc-editor.org/rfc/rfc7519) format of session tokens
+//!
+//! Session tokens are opaque encrypted blobs by default. Assign [JwtConfig] with
+//! [crate::tokens::TokenConfig::jwt] to issue them as signed JWTs instead, which
+//! other services verify with shared secret, or with public keys published by [JwtConfig::jwks].
+//!
+//! Tokens carry standard claims `iss`, `sub`, `aud`, `exp`, `nbf`, `iat` and `jti`,
+//! followed by custom claims of [crate::tokens::Session]. Access tokens are typed
+//! `at+jwt` ([RFC 9068](https://www.rfc-editor.org/rfc/rfc9068)) and refresh tokens
+//! `rt+jwt`, so one is never accepted as another. Tokens past their own `exp`, or
+//! before `nbf`, are rejected within [crate::tokens::TokenConfig::clock_skew],
+//! even if the configured lifetime is longer.
+//!
+//! # Examples
+//!
+//! ```
+//! use humus_terra::jwt::{JwtConfig, JwtKey};
+//! use humus_terra::tokens::TokenConfig;
+//!
+//! let signing_key = JwtKey::ed25519("2024-10", &[7; 32]);
+//! let jwt = JwtConfig::new(signing_key).issuer("https://auth.example.com");
+//!
+//! // Serve this at `/.well-known/jwks.json`
+//! let jwks = jwt.jwks();
+//!
+//! TokenConfig::set(TokenConfig::new("secret", true).audience("api").jwt(jwt));
+//! ```
+//!
+
+use crate::tokens::TokenError;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Map, Value};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+// Browsers limit a cookie to 4096 bytes
+static MAX_JWT_LENGTH: usize = 4096;
+
+///
+/// An algorithm of JWS signature ([RFC 7518](https://www.rfc-editor.org/rfc/rfc7518#section-3.1))
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    ///
+    /// HMAC using SHA-256
+    ///
+    HS256,
+    ///
+    /// HMAC using SHA-512
+    ///
+    HS512,
+    ///
+    /// Ed25519 signature ([RFC 8037](https://www.rfc-editor.org/rfc/rfc8037))
+    ///
+    EdDSA,
+    ///
+    /// ECDSA using P-256 and SHA-256
+    ///
+    ES256,
+}
+
+impl Algorithm {
+    ///
+    /// Get name of algorithm in `alg` header
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::HS256 => "HS256",
+            Algorithm::HS512 => "HS512",
+            Algorithm::EdDSA => "EdDSA",
+            Algorithm::ES256 => "ES256",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "HS256" => Some(Algorithm::HS256),
+            "HS512" => Some(Algorithm::HS512),
+            "EdDSA" => Some(Algorithm::EdDSA),
+            "ES256" => Some(Algorithm::ES256),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+enum KeyMaterial {
+    HS256(Vec<u8>),
+    HS512(Vec<u8>),
+    EdDSA(
+        Option<Box<ed25519_dalek::SigningKey>>,
+        Box<ed25519_dalek::VerifyingKey>,
+    ),
+    ES256(
+        Option<Box<p256::ecdsa::SigningKey>>,
+        Box<p256::ecdsa::VerifyingKey>,
+    ),
+}
+
+///
+/// A key signing or verifying JWTs, identified by `kid` header
+///
+pub struct JwtKey {
+    id: String,
+    material: KeyMaterial,
+}
+
+impl JwtKey {
+    ///
+    /// Create HS256 key from shared secret, which should be at least 32 bytes
+    ///
+    pub fn hs256(id: &str, secret: &[u8]) -> Self {
+        Self::with(id, KeyMaterial::HS256(secret.to_vec()))
+    }
+
+    ///
+    /// Create HS512 key from shared secret, which should be at least 64 bytes
+    ///
+    pub fn hs512(id: &str, secret: &[u8]) -> Self {
+        Self::with(id, KeyMaterial::HS512(secret.to_vec()))
+    }
+
+    ///
+    /// Create EdDSA key from 32 bytes Ed25519 private key
+    ///
+    pub fn ed25519(id: &str, private_key: &[u8; 32]) -> Self {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(private_key);
+        let verifying_key = signing_key.verifying_key();
+        Self::with(
+            id,
+            KeyMaterial::EdDSA(Some(Box::new(signing_key)), Box::new(verifying_key)),
+        )
+    }
+
+    ///
+    /// Create EdDSA key only verifying tokens, from 32 bytes Ed25519 public key
+    ///
+    pub fn ed25519_public(id: &str, public_key: &[u8; 32]) -> Result<Self, TokenError> {
+        let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(public_key)
+            .map_err(|_| TokenError::InvalidKey)?;
+        Ok(Self::with(
+            id,
+            KeyMaterial::EdDSA(None, Box::new(verifying_key)),
+        ))
+    }
+
+    ///
+    /// Create ES256 key from 32 bytes P-256 private scalar
+    ///
+    pub fn es256(id: &str, private_key: &[u8; 32]) -> Result<Self, TokenError> {
+        let signing_key =
+            p256::ecdsa::SigningKey::from_slice(private_key).map_err(|_| TokenError::InvalidKey)?;
+        let verifying_key = *signing_key.verifying_key();
+        Ok(Self::with(
+            id,
+            KeyMaterial::ES256(Some(Box::new(signing_key)), Box::new(verifying_key)),
+        ))
+    }
+
+    ///
+    /// Create ES256 key only verifying tokens, from SEC1 encoded P-256 public key
+    ///
+    pub fn es256_public(id: &str, public_key: &[u8]) -> Result<Self, TokenError> {
+        let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+            .map_err(|_| TokenError::InvalidKey)?;
+        Ok(Self::with(
+            id,
+            KeyMaterial::ES256(None, Box::new(verifying_key)),
+        ))
+    }
+
+    fn with(id: &str, material: KeyMaterial) -> Self {
+        Self {
+            id: id.to_string(),
+            material,
+        }
+    }
+
+    ///
+    /// Get ID of key
+    ///
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    ///
+    /// Get algorithm of key
+    ///
+    pub fn algorithm(&self) -> Algorithm {
+        match &self.material {
+            KeyMaterial::HS256(_) => Algorithm::HS256,
+            KeyMaterial::HS512(_) => Algorithm::HS512,
+            KeyMaterial::EdDSA(..) => Algorithm::EdDSA,
+            KeyMaterial::ES256(..) => Algorithm::ES256,
+        }
+    }
+
+    fn can_sign(&self) -> bool {
+        match &self.material {
+            KeyMaterial::HS256(_) | KeyMaterial::HS512(_) => true,
+            KeyMaterial::EdDSA(signing_key, _) => signing_key.is_some(),
+            KeyMaterial::ES256(signing_key, _) => signing_key.is_some(),
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Option<Vec<u8>> {
+        match &self.material {
+            KeyMaterial::HS256(secret) => {
+                let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret).ok()?;
+                mac.update(message);
+                Some(mac.finalize().into_bytes().to_vec())
+            }
+            KeyMaterial::HS512(secret) => {
+                let mut mac = Hmac::<sha2::Sha512>::new_from_slice(secret).ok()?;
+                mac.update(message);
+                Some(mac.finalize().into_bytes().to_vec())
+            }
+            KeyMaterial::EdDSA(signing_key, _) => {
+                Some(signing_key.as_ref()?.sign(message).to_bytes().to_vec())
+            }
+            KeyMaterial::ES256(signing_key, _) => {
+                let signature: p256::ecdsa::Signature = signing_key.as_ref()?.sign(message);
+                Some(signature.to_bytes().to_vec())
+            }
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.material {
+            KeyMaterial::HS256(secret) => Hmac::<sha2::Sha256>::new_from_slice(secret)
+                .is_ok_and(|mac| mac.chain_update(message).verify_slice(signature).is_ok()),
+            KeyMaterial::HS512(secret) => Hmac::<sha2::Sha512>::new_from_slice(secret)
+                .is_ok_and(|mac| mac.chain_update(message).verify_slice(signature).is_ok()),
+            KeyMaterial::EdDSA(_, verifying_key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| verifying_key.verify_strict(message, &signature).is_ok()),
+            KeyMaterial::ES256(_, verifying_key) => p256::ecdsa::Signature::from_slice(signature)
+                .is_ok_and(|signature| verifying_key.verify(message, &signature).is_ok()),
+        }
+    }
+
+    // Public key in JWK format; Shared secrets are never exported
+    fn jwk(&self) -> Option<Value> {
+        match &self.material {
+            KeyMaterial::HS256(_) | KeyMaterial::HS512(_) => None,
+            KeyMaterial::EdDSA(_, verifying_key) => Some(json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": BASE64_URL_SAFE_NO_PAD.encode(verifying_key.as_bytes()),
+                "kid": self.id,
+                "alg": "EdDSA",
+                "use": "sig",
+            })),
+            KeyMaterial::ES256(_, verifying_key) => {
+                let point = verifying_key.to_encoded_point(false);
+                Some(json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "x": BASE64_URL_SAFE_NO_PAD.encode(point.x()?),
+                    "y": BASE64_URL_SAFE_NO_PAD.encode(point.y()?),
+                    "kid": self.id,
+                    "alg": "ES256",
+                    "use": "sig",
+                }))
+            }
+        }
+    }
+}
+
+///
+/// A configuration of JWT format
+///
+/// Tokens are signed with the signing key, and verified with it or other
+/// verifying keys. Tokens signed with verifying keys are re-issued like
+/// tokens of retired keys in encrypted format.
+///
+/// Only algorithms of configured keys are accepted unless restricted further
+/// with [JwtConfig::algorithms]; `none` is never accepted.
+///
+pub struct JwtConfig {
+    keys: Vec<JwtKey>,
+    issuer: Option<String>,
+    algorithms: Option<Vec<Algorithm>>,
+}
+
+impl JwtConfig {
+    ///
+    /// Create new configuration signing tokens with given key
+    ///
+    /// # Panics
+    ///
+    /// Panics if key only verifies tokens.
+    ///
+    pub fn new(signing_key: JwtKey) -> Self {
+        assert!(signing_key.can_sign(), "JWT signing key must be private");
+
+        Self {
+            keys: vec![signing_key],
+            issuer: None,
+            algorithms: None,
+        }
+    }
+
+    ///
+    /// Accept tokens signed with given key
+    ///
+    pub fn verify_key(mut self, key: JwtKey) -> Self {
+        if key.id != self.keys[0].id {
+            self.keys.retain(|k| k.id != key.id);
+            self.keys.push(key);
+        }
+        self
+    }
+
+    ///
+    /// Set issuer of tokens; `iss` claim which tokens must carry
+    ///
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    ///
+    /// Restrict algorithms which tokens are accepted with
+    ///
+    pub fn algorithms(mut self, algorithms: &[Algorithm]) -> Self {
+        self.algorithms = Some(algorithms.to_vec());
+        self
+    }
+
+    ///
+    /// Get JSON Web Key Set of public keys, to be published for other services
+    ///
+    pub fn jwks(&self) -> Value {
+        let keys = self.keys.iter().filter_map(JwtKey::jwk).collect::<Vec<_>>();
+        json!({ "keys": keys })
+    }
+
+    fn allows(&self, algorithm: Algorithm) -> bool {
+        match &self.algorithms {
+            Some(algorithms) => algorithms.contains(&algorithm),
+            None => self.keys.iter().any(|k| k.algorithm() == algorithm),
+        }
+    }
+
+    ///
+    /// Sign claims into compact JWS with `typ` header.
+    /// `iss` is filled in with configured issuer.
+    ///
+    pub(crate) fn encode(
+        &self,
+        typ: &str,
+        mut claims: Map<String, Value>,
+    ) -> Result<String, TokenError> {
+        let key = &self.keys[0];
+        if let Some(issuer) = &self.issuer {
+            claims.insert("iss".to_string(), Value::from(issuer.as_str()));
+        }
+
+        let mut header = Map::new();
+        header.insert("alg".to_string(), Value::from(key.algorithm().as_str()));
+        header.insert("typ".to_string(), Value::from(typ));
+        if !key.id.is_empty() {
+            header.insert("kid".to_string(), Value::from(key.id.as_str()));
+        }
+
+        let mut jwt = format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(Value::Object(header).to_string()),
+            BASE64_URL_SAFE_NO_PAD.encode(Value::Object(claims).to_string()),
+        );
+        let signature = key.sign(jwt.as_bytes()).ok_or(TokenError::InvalidKey)?;
+        jwt.push('.');
+        jwt.push_str(&BASE64_URL_SAFE_NO_PAD.encode(signature));
+
+        Ok(jwt)
+    }
+
+    ///
+    /// Verify compact JWS of given `typ`, and return its claims and whether
+    /// it's signed with key other than signing one.
+    ///
+    /// `aud` must contain `audience` unless it's empty.
+    /// Tokens past `exp` are accepted only unless `enforce_expiry`,
+    /// for caller which rotates them.
+    ///
+    pub(crate) fn decode(
+        &self,
+        jwt: &str,
+        typ: &str,
+        audience: &str,
+        clock_skew: Duration,
+        enforce_expiry: bool,
+    ) -> Result<(Map<String, Value>, bool), TokenError> {
+        if jwt.len() > MAX_JWT_LENGTH {
+            return Err(TokenError::Malformed);
+        }
+
+        let mut parts = jwt.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+
+        let decode = |part: &str| -> Result<Map<String, Value>, TokenError> {
+            let json = BASE64_URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| TokenError::Malformed)?;
+            match serde_json::from_slice(&json) {
+                Ok(Value::Object(object)) => Ok(object),
+                _ => Err(TokenError::Malformed),
+            }
+        };
+
+        let header_json = decode(header)?;
+        // No extension is understood
+        if header_json.contains_key("crit") || header_json.get("typ") != Some(&Value::from(typ)) {
+            return Err(TokenError::Tampered);
+        }
+
+        let algorithm = header_json
+            .get("alg")
+            .and_then(Value::as_str)
+            .and_then(Algorithm::parse)
+            .filter(|algorithm| self.allows(*algorithm))
+            .ok_or(TokenError::Tampered)?;
+        let id = match header_json.get("kid") {
+            None => "",
+            Some(Value::String(id)) => id.as_str(),
+            Some(_) => return Err(TokenError::Malformed),
+        };
+        // Key decides algorithm, so HMAC is never verified with public key
+        let (index, key) = self
+            .keys
+            .iter()
+            .enumerate()
+            .find(|(_, key)| key.id == id && key.algorithm() == algorithm)
+            .ok_or(TokenError::Tampered)?;
+
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        let message = &jwt[..header.len() + 1 + payload.len()];
+        if !key.verify(message.as_bytes(), &signature) {
+            return Err(TokenError::Tampered);
+        }
+
+        let claims = decode(payload)?;
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str()) {
+                return Err(TokenError::Tampered);
+            }
+        }
+        if !audience.is_empty() {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud == audience),
+                _ => false,
+            };
+            if !matches {
+                return Err(TokenError::Tampered);
+            }
+        }
+
+        // NumericDate may be fractional
+        let numeric_date = |name: &str| {
+            claims
+                .get(name)
+                .and_then(Value::as_f64)
+                .ok_or(TokenError::Malformed)
+        };
+        let now = Utc::now().timestamp() as f64;
+        let skew = clock_skew.as_secs_f64();
+        // Numeric `exp` is required even if it's not enforced
+        if now > numeric_date("exp")? + skew && enforce_expiry {
+            return Err(TokenError::Expired);
+        }
+        if now + skew < numeric_date("nbf")? {
+            return Err(TokenError::NotYetValid);
+        }
+
+        Ok((claims, index != 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SKEW: Duration = Duration::from_secs(30);
+
+    fn claims() -> Map<String, Value> {
+        let now = Utc::now().timestamp();
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), Value::from("alice"));
+        claims.insert("aud".to_string(), Value::from("api"));
+        claims.insert("nbf".to_string(), Value::from(now));
+        claims.insert("exp".to_string(), Value::from(now + 60));
+        claims
+    }
+
+    #[test]
+    fn algorithms() {
+        let keys = [
+            JwtKey::hs256("h1", &[1; 32]),
+            JwtKey::hs512("h5", &[2; 64]),
+            JwtKey::ed25519("ed", &[3; 32]),
+            JwtKey::es256("es", &[4; 32]).unwrap(),
+        ];
+
+        for key in keys {
+            let algorithm = key.algorithm();
+            let config = JwtConfig::new(key).issuer("auth");
+            let jwt = config.encode("at+jwt", claims()).unwrap();
+
+            let (decoded, rekey) = config.decode(&jwt, "at+jwt", "api", SKEW, true).unwrap();
+            assert_eq!(decoded["sub"], "alice");
+            assert_eq!(decoded["iss"], "auth");
+            assert!(!rekey);
+
+            assert!(matches!(
+                config.decode(&jwt, "rt+jwt", "api", SKEW, true),
+                Err(TokenError::Tampered)
+            ));
+            assert!(matches!(
+                config.decode(&jwt, "at+jwt", "admin", SKEW, true),
+                Err(TokenError::Tampered)
+            ));
+
+            // Payload can't be modified
+            let (header, rest) = jwt.split_once('.').unwrap();
+            let (_, signature) = rest.split_once('.').unwrap();
+            let mut forged = claims();
+            forged.insert("sub".to_string(), Value::from("mallory"));
+            forged.insert("iss".to_string(), Value::from("auth"));
+            let forged = format!(
+                "{}.{}.{}",
+                header,
+                BASE64_URL_SAFE_NO_PAD.encode(Value::Object(forged).to_string()),
+                signature
+            );
+            assert!(matches!(
+                config.decode(&forged, "at+jwt", "api", SKEW, true),
+                Err(TokenError::Tampered)
+            ));
+
+            let restricted = config.algorithms(&[]);
+            assert!(
+                matches!(
+                    restricted.decode(&jwt, "at+jwt", "api", SKEW, true),
+                    Err(TokenError::Tampered)
+                ),
+                "{} is not allowed",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn algorithm_confusion() {
+        let es256 = JwtKey::es256("k", &[4; 32]).unwrap();
+        let public_key = es256.jwk().unwrap();
+        let config = JwtConfig::new(es256);
+
+        // HMAC keyed with public key is rejected
+        let attacker = JwtConfig::new(JwtKey::hs256("k", public_key.to_string().as_bytes()));
+        let jwt = attacker.encode("at+jwt", claims()).unwrap();
+        assert!(matches!(
+            config.decode(&jwt, "at+jwt", "", SKEW, true),
+            Err(TokenError::Tampered)
+        ));
+
+        let header = BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"at+jwt","kid":"k"}"#);
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(r#"{"sub":"alice"}"#);
+        let unsigned = format!("{}.{}.", header, payload);
+        assert!(matches!(
+            config.decode(&unsigned, "at+jwt", "", SKEW, true),
+            Err(TokenError::Tampered)
+        ));
+
+        assert!(matches!(
+            config.decode("a.b", "at+jwt", "", SKEW, true),
+            Err(TokenError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn jwks_and_rotation() {
+        let old = JwtKey::ed25519("old", &[5; 32]);
+        let public_key = old.jwk().unwrap();
+        let jwt = JwtConfig::new(old).encode("at+jwt", claims()).unwrap();
+
+        let x = BASE64_URL_SAFE_NO_PAD
+            .decode(public_key["x"].as_str().unwrap())
+            .unwrap();
+        let verifying = JwtKey::ed25519_public("old", &x.try_into().unwrap()).unwrap();
+        let config = JwtConfig::new(JwtKey::hs256("new", &[1; 32])).verify_key(verifying);
+        let (_, rekey) = config.decode(&jwt, "at+jwt", "", SKEW, true).unwrap();
+        assert!(rekey);
+
+        let jwks = config.jwks();
+        assert_eq!(jwks["keys"].as_array().unwrap().len(), 1);
+        assert_eq!(jwks["keys"][0]["kid"], "old");
+        assert_eq!(jwks["keys"][0]["crv"], "Ed25519");
+
+        let es256 = JwtKey::es256("es", &[4; 32]).unwrap().jwk().unwrap();
+        assert_eq!(es256["kty"], "EC");
+        assert_eq!(es256["y"].as_str().unwrap().len(), 43);
+    }
+
+    #[test]
+    fn lifetime() {
+        let config = JwtConfig::new(JwtKey::hs256("", &[1; 32]));
+        let now = Utc::now().timestamp();
+        let decode = |name: &str, value: Value| {
+            let mut claims = claims();
+            claims.insert(name.to_string(), value);
+            let jwt = config.encode("at+jwt", claims).unwrap();
+            config.decode(&jwt, "at+jwt", "api", SKEW, true)
+        };
+
+        assert!(matches!(
+            decode("exp", Value::from(now - 60)),
+            Err(TokenError::Expired)
+        ));
+        assert!(matches!(
+            decode("nbf", Value::from(now + 60)),
+            Err(TokenError::NotYetValid)
+        ));
+        // Within clock skew
+        assert!(decode("exp", Value::from(now - 10)).is_ok());
+        assert!(decode("nbf", Value::from(now + 10)).is_ok());
+        assert!(decode("exp", Value::from(now as f64 + 0.5)).is_ok());
+
+        let mut expired = claims();
+        expired.insert("exp".to_string(), Value::from(now - 60));
+        let expired = config.encode("at+jwt", expired).unwrap();
+        assert!(config
+            .decode(&expired, "at+jwt", "api", SKEW, false)
+            .is_ok());
+
+        assert!(matches!(
+            decode("exp", Value::from((now + 60).to_string())),
+            Err(TokenError::Malformed)
+        ));
+        let mut claims = claims();
+        claims.remove("nbf");
+        let jwt = config.encode("at+jwt", claims).unwrap();
+        assert!(matches!(
+            config.decode(&jwt, "at+jwt", "api", SKEW, true),
+            Err(TokenError::Malformed)
+        ));
+    }
+}
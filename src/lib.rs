@@ -7,33 +7,58 @@
 //!
 //! # Features
 //!
-//! - HTTP/2
+//! - HTTP/1.1 and HTTP/2, negotiated automatically
+//! - WebSocket over HTTP/1.1 and HTTP/2
 //! - Asynchronous Design
 //!
 
+pub mod body;
+pub mod compression;
+pub mod conditional;
+pub mod connection;
+pub mod cors;
+pub mod decompression;
 mod encrypt;
 mod error;
+pub mod jwt;
+pub mod proxy;
+mod proxy_protocol;
+pub mod range;
 pub mod response;
 pub mod route;
+pub mod security;
+pub mod sse;
+pub mod statics;
 pub mod terminal;
+pub mod token_store;
 pub mod tokens;
+pub mod websocket;
 
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::server::conn::http2;
+use http_body_util::LengthLimitError;
+use hyper::body::Incoming;
+use hyper::header::{ACCEPT_ENCODING, ORIGIN};
 use hyper::service::service_fn;
-use hyper::{Request, Response, StatusCode};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use hyper_util::server::conn::auto;
 
-use crate::response::ResponseBuilder;
-use crate::route::{configure_all, match_route, shutdown_all, Route};
+use crate::body::Body;
+use crate::connection::ConnectionInfo;
+use crate::cors::Cors;
+use crate::decompression::{DecodingError, Decompression};
+use crate::proxy::TrustedProxies;
+use crate::response::{ResponseBuilder, ResponseConfig};
+use crate::route::{configure_all, resolve_route, shutdown_all, Route};
 use std::convert::Infallible;
 use std::error::Error;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
 
+static PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 struct TokioExecutor;
 
@@ -52,6 +77,11 @@ pub struct App {
     port: u16,
     shutdown_duration: Duration,
     root_route: Arc<dyn Route + Send + Sync>,
+    trusted_proxies: TrustedProxies,
+    proxy_protocol: bool,
+    response_config: Option<Arc<ResponseConfig>>,
+    decompression: Option<Decompression>,
+    connection_id: AtomicU64,
 }
 
 impl App {
@@ -80,30 +110,175 @@ impl App {
             port,
             shutdown_duration,
             root_route,
+            trusted_proxies: TrustedProxies::new(),
+            proxy_protocol: false,
+            response_config: None,
+            decompression: None,
+            connection_id: AtomicU64::new(0),
         }
     }
 
+    ///
+    /// Trust forwarding headers (`Forwarded`, `X-Forwarded-*`) sent by given proxies
+    ///
+    /// Routes can read the original client with [proxy::ClientInfo::from_request].
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// use humus_terra::App;
+    /// use humus_terra::proxy::TrustedProxies;
+    ///
+    /// let app = App::new(8080, Duration::from_secs(10), ...)
+    ///     .with_trusted_proxies(TrustedProxies::parse(&["10.0.0.0/8"])?);
+    /// ```
+    ///
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    ///
+    /// Require [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+    /// (v1 or v2) header at the beginning of every connection
+    ///
+    /// Enable this only if the application is deployed behind TCP load balancers
+    /// sending PROXY protocol headers; Connections without valid header are dropped.
+    /// Original addresses are available in [connection::ConnectionInfo].
+    ///
+    pub fn with_proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    ///
+    /// Use given configuration for responses of this application
+    ///
+    /// Without this, the process-wide configuration is used.
+    /// Routes can override this for their subtree; See [route::Route::response_config].
+    ///
+    pub fn with_response_config(mut self, config: ResponseConfig) -> Self {
+        self.response_config = Some(Arc::new(config));
+        self
+    }
+
+    ///
+    /// Decompress request bodies encoded with `Content-Encoding` before they reach routes
+    ///
+    /// See [decompression] for details.
+    ///
+    pub fn with_decompression(mut self, decompression: Decompression) -> Self {
+        self.decompression = Some(decompression);
+        self
+    }
+
     async fn configure(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         configure_all(self.root_route.clone()).await
     }
 
-    async fn map(&self, request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
-        let route = match match_route(request.uri().path(), self.root_route.clone()) {
-            None => {
-                return Ok(ResponseBuilder::new()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Full::from(Bytes::new()))
-                    .unwrap())
+    async fn map(
+        &self,
+        request: Request<Incoming>,
+        connection: ConnectionInfo,
+    ) -> Result<Response<Body>, Infallible> {
+        let mut request = request.map(Body::new);
+        let connection = connection.with_version(request.version());
+        let client = self.trusted_proxies.resolve(&request, &connection);
+        request.extensions_mut().insert(connection);
+        request.extensions_mut().insert(client);
+
+        let matched = resolve_route(request.uri().path(), self.root_route.clone());
+        let response_config = match matched.response_config.or(self.response_config.clone()) {
+            Some(config) => config,
+            None => ResponseConfig::global(),
+        };
+        request.extensions_mut().insert(response_config.clone());
+        request.extensions_mut().insert(matched.subpath);
+
+        let nonce = match &response_config.security_headers {
+            None => None,
+            Some(security_headers) => security_headers.prepare(&mut request),
+        };
+        let origin = request.headers().get(ORIGIN).cloned();
+        let accept_encoding = request.headers().get(ACCEPT_ENCODING).cloned();
+        let is_head = request.method() == Method::HEAD;
+        let preconditions = response_config
+            .auto_etag
+            .as_ref()
+            .map(|_| (request.method().clone(), request.headers().clone()));
+
+        let mut response = match (&response_config.cors, matched.route) {
+            (Some(cors), _) if Cors::is_preflight(&request) => {
+                cors.preflight(&request, &response_config)
             }
-            Some(route) => route,
+            (_, None) => ResponseBuilder::with_config(&response_config)
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+            (_, Some(route)) => self.handle(route, request, &response_config).await,
         };
 
+        if let (Some(auto_etag), Some((method, headers))) =
+            (&response_config.auto_etag, &preconditions)
+        {
+            response = auto_etag.apply(method, headers, response);
+        }
+        if let Some(cors) = &response_config.cors {
+            cors.apply(origin.as_ref(), &mut response);
+        }
+        if let Some(security_headers) = &response_config.security_headers {
+            security_headers.apply(nonce.as_ref(), &mut response);
+        }
+        if let Some(compression) = &response_config.compression {
+            response = compression
+                .apply(accept_encoding.as_ref(), is_head, response)
+                .await;
+        }
+
+        Ok(response)
+    }
+
+    async fn handle(
+        &self,
+        route: Arc<dyn Route + Send + Sync>,
+        mut request: Request<Body>,
+        response_config: &ResponseConfig,
+    ) -> Response<Body> {
+        if let Some(decompression) = &self.decompression {
+            match decompression.apply(&mut request) {
+                Ok(()) => {}
+                Err(DecodingError::Unsupported(_)) => {
+                    return ResponseBuilder::with_config(response_config)
+                        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                        .header(ACCEPT_ENCODING, decompression.accept_encoding())
+                        .body(Body::empty())
+                        .unwrap();
+                }
+                Err(DecodingError::Malformed | DecodingError::Corrupt(_)) => {
+                    return ResponseBuilder::with_config(response_config)
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+            }
+        }
+
         match route.handle(request).await {
-            Ok(response) => Ok(response),
-            Err(error) => Ok(ResponseBuilder::new()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::from(error.to_string()))
-                .unwrap()),
+            Ok(response) => response,
+            Err(error) => {
+                let status = if error.downcast_ref::<LengthLimitError>().is_some() {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                } else if error.downcast_ref::<DecodingError>().is_some() {
+                    StatusCode::BAD_REQUEST
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+
+                ResponseBuilder::with_config(response_config)
+                    .status(status)
+                    .body(Body::from(error.to_string()))
+                    .unwrap()
+            }
         }
     }
 
@@ -152,18 +327,54 @@ impl App {
 
         loop {
             tokio::select! {
-                Ok((stream, _)) = listener.accept() => {
-                    let io = TokioIo::new(stream);
+                Ok((mut stream, peer_addr)) = listener.accept() => {
+                    let local_addr = match stream.local_addr() {
+                        Ok(addr) => addr,
+                        Err(err) => {
+                            log!(fail "Failed to get local address: {}", err);
+                            continue;
+                        }
+                    };
+                    let mut connection = ConnectionInfo::new(
+                        self.connection_id.fetch_add(1, Ordering::Relaxed),
+                        peer_addr,
+                        local_addr,
+                    );
+
                     let app = self.clone();
 
                     tokio::task::spawn(async move {
-                        if let Err(err) = http2::Builder::new(TokioExecutor)
-                            .serve_connection(io, service_fn(move |req| {
+                        if app.proxy_protocol {
+                            let header = tokio::time::timeout(
+                                PROXY_HEADER_TIMEOUT,
+                                proxy_protocol::read_header(&mut stream),
+                            ).await;
+
+                            match header {
+                                Ok(Ok(header)) => connection = connection.with_proxy_header(header),
+                                Ok(Err(err)) => {
+                                    log!(warn "PROXY protocol error from {}: {}", peer_addr, err);
+                                    return;
+                                }
+                                Err(_) => {
+                                    log!(warn "PROXY protocol header timed out from {}", peer_addr);
+                                    return;
+                                }
+                            }
+                        }
+
+                        let io = TokioIo::new(stream);
+                        let mut builder = auto::Builder::new(TokioExecutor);
+                        // Extended CONNECT bootstraps WebSocket over HTTP/2 (RFC 8441)
+                        builder.http2().enable_connect_protocol();
+                        if let Err(err) = builder
+                            .serve_connection_with_upgrades(io, service_fn(move |req| {
                                 let scoped_app = app.clone();
-                                async move { scoped_app.clone().map(req).await }
+                                let connection = connection.clone();
+                                async move { scoped_app.clone().map(req, connection).await }
                             }))
                             .await {
-                            log!(fail "HTTP2 error: {}", err);
+                            log!(fail "HTTP error: {}", err);
                         }
                     });
                 },
@@ -0,0 +1,398 @@
+//!
+//! A module that resolves original client of requests forwarded by trusted proxies
+//!
+//! When the application is deployed behind reverse proxies or load balancers,
+//! the peer address of every connection is the one of the proxy.
+//! Proxies report the original client with `Forwarded` ([RFC 7239](https://www.rfc-editor.org/rfc/rfc7239))
+//! or de-facto standard `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+//! These headers are honoured only if the peer is one of [TrustedProxies].
+//!
+
+use crate::connection::ConnectionInfo;
+use crate::error::Error;
+use hyper::header::{HeaderName, FORWARDED, HOST};
+use hyper::Request;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+///
+/// A list of networks whose forwarding headers are trusted
+///
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    ///
+    /// Create new empty list; no proxy is trusted
+    ///
+    pub fn new() -> Self {
+        Self { networks: vec![] }
+    }
+
+    ///
+    /// Create new list from CIDR notations (e.g. `10.0.0.0/8`, `::1/128`)
+    ///
+    /// A bare address is treated as a network that contains only itself.
+    ///
+    pub fn parse(cidrs: &[&str]) -> Result<Self, Error> {
+        let mut proxies = Self::new();
+        for cidr in cidrs {
+            let network = match IpNet::from_str(cidr) {
+                Ok(network) => network,
+                Err(_) => IpAddr::from_str(cidr)
+                    .map(IpNet::from)
+                    .map_err(|_| Error::from(format!("Invalid CIDR: {}", cidr)))?,
+            };
+            proxies.networks.push(network);
+        }
+
+        Ok(proxies)
+    }
+
+    ///
+    /// Check if given address is trusted
+    ///
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        self.networks.iter().any(|network| network.contains(&addr))
+    }
+
+    pub(crate) fn resolve<B>(
+        &self,
+        request: &Request<B>,
+        connection: &ConnectionInfo,
+    ) -> ClientInfo {
+        let peer = connection.remote_addr().ip().to_canonical();
+        let scheme = if connection.tls().is_some() {
+            "https"
+        } else {
+            "http"
+        };
+
+        let mut client = ClientInfo {
+            addr: Some(peer),
+            scheme: scheme.to_string(),
+            host: direct_host(request),
+        };
+
+        if !self.contains(peer) {
+            return client;
+        }
+
+        let hops = if request.headers().contains_key(FORWARDED) {
+            forwarded_hops(request)
+        } else {
+            x_forwarded_hops(request)
+        };
+
+        // Walk backwards from the nearest proxy;
+        // The first untrusted address is the one of client
+        for hop in hops.iter().rev() {
+            let addr = match hop.addr {
+                // Obfuscated or unknown; Client is hidden behind it
+                None => {
+                    client.addr = None;
+                    break;
+                }
+                Some(addr) => addr.to_canonical(),
+            };
+
+            client.addr = Some(addr);
+            if let Some(proto) = &hop.proto {
+                client.scheme = proto.to_ascii_lowercase();
+            }
+            if let Some(host) = &hop.host {
+                client.host = Some(host.clone());
+            }
+
+            if !self.contains(addr) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+///
+/// A struct that contains information about original client of request
+///
+/// If the request was forwarded by [TrustedProxies], this describes the
+/// original client reported by proxies. Otherwise, this describes the peer.
+///
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    addr: Option<IpAddr>,
+    scheme: String,
+    host: Option<String>,
+}
+
+impl ClientInfo {
+    ///
+    /// Retrieve client information from request
+    ///
+    /// Returns `None` if the request didn't come through [crate::App].
+    ///
+    pub fn from_request<B>(request: &Request<B>) -> Option<&Self> {
+        request.extensions().get::<Self>()
+    }
+
+    ///
+    /// Get address of original client
+    ///
+    /// Returns `None` if a proxy hid it, e.g. with obfuscated `Forwarded: for=_hidden`.
+    ///
+    pub fn addr(&self) -> Option<IpAddr> {
+        self.addr
+    }
+
+    ///
+    /// Get scheme of original request (e.g. `http`, `https`)
+    ///
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    ///
+    /// Get host of original request
+    ///
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    ///
+    /// Check if original request was made over secure channel
+    ///
+    pub fn is_secure(&self) -> bool {
+        self.scheme == "https" || self.scheme == "wss"
+    }
+}
+
+#[derive(Debug, Default)]
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+fn direct_host<B>(request: &Request<B>) -> Option<String> {
+    if let Some(authority) = request.uri().authority() {
+        return Some(authority.to_string());
+    }
+
+    request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string)
+}
+
+fn header_values<'a, B>(request: &'a Request<B>, name: &HeaderName) -> Vec<&'a str> {
+    request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect()
+}
+
+fn x_forwarded_hops<B>(request: &Request<B>) -> Vec<Hop> {
+    let mut hops = header_values(request, &X_FORWARDED_FOR)
+        .into_iter()
+        .map(|node| Hop {
+            addr: parse_node(node),
+            ..Hop::default()
+        })
+        .collect::<Vec<_>>();
+
+    // Proxies usually overwrite these headers instead of appending to them;
+    // Align values to the nearest hops so that overwritten ones apply to the whole chain
+    for (name, is_proto) in [(&X_FORWARDED_PROTO, true), (&X_FORWARDED_HOST, false)] {
+        let values = header_values(request, name);
+        if values.is_empty() || hops.is_empty() {
+            continue;
+        }
+
+        let offset = hops.len().saturating_sub(values.len());
+        for (i, value) in values.iter().enumerate() {
+            let hop = match hops.get_mut(offset + i) {
+                None => break,
+                Some(hop) => hop,
+            };
+
+            if is_proto {
+                hop.proto = Some(value.to_string());
+            } else {
+                hop.host = Some(value.to_string());
+            }
+        }
+    }
+
+    hops
+}
+
+fn forwarded_hops<B>(request: &Request<B>) -> Vec<Hop> {
+    let mut hops = vec![];
+
+    for value in request.headers().get_all(FORWARDED) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+
+        for element in split_unquoted(value, ',') {
+            let mut hop = Hop::default();
+            for pair in split_unquoted(element, ';') {
+                let (key, value) = match pair.split_once('=') {
+                    None => continue,
+                    Some((key, value)) => (key.trim(), unquote(value.trim())),
+                };
+
+                if key.eq_ignore_ascii_case("for") {
+                    hop.addr = parse_node(value);
+                } else if key.eq_ignore_ascii_case("proto") {
+                    hop.proto = Some(value.to_string());
+                } else if key.eq_ignore_ascii_case("host") {
+                    hop.host = Some(value.to_string());
+                }
+            }
+            hops.push(hop);
+        }
+    }
+
+    hops
+}
+
+fn split_unquoted(value: &str, delimiter: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == delimiter && !quoted {
+            parts.push(value[start..i].trim());
+            start = i + 1;
+        }
+    }
+    parts.push(value[start..].trim());
+
+    parts.into_iter().filter(|part| !part.is_empty()).collect()
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(addr) = IpAddr::from_str(node) {
+        return Some(addr);
+    }
+    if let Ok(addr) = SocketAddr::from_str(node) {
+        return Some(addr.ip());
+    }
+
+    // `[2001:db8::1]` without port
+    let node = node.strip_prefix('[')?.strip_suffix(']')?;
+    IpAddr::from_str(node).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(proxies: &[&str], peer: &str, headers: &[(&str, &str)]) -> ClientInfo {
+        let mut builder = Request::builder().uri("/").header(HOST, "direct.example");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let request = builder.body(()).unwrap();
+
+        let peer = SocketAddr::from_str(peer).unwrap();
+        let connection = ConnectionInfo::new(0, peer, peer);
+
+        TrustedProxies::parse(proxies)
+            .unwrap()
+            .resolve(&request, &connection)
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let client = resolve(
+            &["10.0.0.0/8"],
+            "192.0.2.1:1234",
+            &[
+                ("x-forwarded-for", "203.0.113.7"),
+                ("x-forwarded-proto", "https"),
+            ],
+        );
+
+        assert_eq!(client.addr(), Some(IpAddr::from_str("192.0.2.1").unwrap()));
+        assert_eq!(client.scheme(), "http");
+        assert_eq!(client.host(), Some("direct.example"));
+    }
+
+    #[test]
+    fn x_forwarded() {
+        let client = resolve(
+            &["10.0.0.0/8"],
+            "10.0.0.2:1234",
+            &[
+                ("x-forwarded-for", "198.51.100.3, 203.0.113.7, 10.0.0.1"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "api.example"),
+            ],
+        );
+
+        assert_eq!(
+            client.addr(),
+            Some(IpAddr::from_str("203.0.113.7").unwrap())
+        );
+        assert!(client.is_secure());
+        assert_eq!(client.host(), Some("api.example"));
+    }
+
+    #[test]
+    fn forwarded() {
+        let client = resolve(
+            &["10.0.0.0/8", "::1"],
+            "[::1]:1234",
+            &[(
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https;host=\"a.example\", for=10.0.0.1",
+            )],
+        );
+
+        assert_eq!(
+            client.addr(),
+            Some(IpAddr::from_str("2001:db8::1").unwrap())
+        );
+        assert_eq!(client.scheme(), "https");
+        assert_eq!(client.host(), Some("a.example"));
+    }
+
+    #[test]
+    fn forwarded_obfuscated() {
+        let client = resolve(
+            &["10.0.0.0/8"],
+            "10.0.0.2:1234",
+            &[("forwarded", "for=_hidden, for=10.0.0.1;proto=https")],
+        );
+
+        assert_eq!(client.addr(), None);
+        assert!(client.is_secure());
+    }
+}
@@ -0,0 +1,304 @@
+//!
+//! A module that parses [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+//! headers sent by TCP load balancers ahead of the actual stream
+//!
+
+use crate::connection::TlsInfo;
+use crate::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+static V1_PREFIX: &[u8] = b"PROXY";
+static V1_MAX_LENGTH: usize = 107;
+
+static V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+///
+/// Addresses of original connection reported by PROXY protocol header
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    pub tls: Option<TlsInfo>,
+}
+
+///
+/// Read PROXY protocol header (v1 or v2) from the beginning of stream.
+///
+/// This reads exactly the bytes of the header, so that the rest of stream
+/// can be handed to HTTP implementation as is.
+///
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<ProxyHeader, Error> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await.map_err(Error::from)?;
+
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(Error::new("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await.map_err(Error::from)?);
+        }
+
+        return parse_v1(&line);
+    }
+
+    if prefix != V2_SIGNATURE[..5] {
+        return Err(Error::new("Missing PROXY protocol header"));
+    }
+
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&prefix);
+    stream
+        .read_exact(&mut header[5..])
+        .await
+        .map_err(Error::from)?;
+
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await.map_err(Error::from)?;
+
+    parse_v2(&header, &payload)
+}
+
+fn parse_v1(line: &[u8]) -> Result<ProxyHeader, Error> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or(Error::new("Malformed PROXY v1 header"))?;
+
+    let parts = line.split(' ').collect::<Vec<&str>>();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let parse_addr = |addr: &str, port: &str| -> Result<SocketAddr, Error> {
+                let ip = IpAddr::from_str(addr).map_err(Error::from)?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(Error::new("Address family mismatched in PROXY v1 header"));
+                }
+                let port = u16::from_str(port).map_err(Error::from)?;
+                Ok(SocketAddr::new(ip, port))
+            };
+
+            Ok(ProxyHeader {
+                source: Some(parse_addr(source, source_port)?),
+                destination: Some(parse_addr(destination, destination_port)?),
+                tls: None,
+            })
+        }
+        _ => Err(Error::new("Malformed PROXY v1 header")),
+    }
+}
+
+fn parse_v2(header: &[u8; 16], payload: &[u8]) -> Result<ProxyHeader, Error> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(Error::new("Invalid PROXY v2 signature"));
+    }
+
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0F;
+    if version != 2 {
+        return Err(Error::new("Unsupported PROXY protocol version"));
+    }
+
+    // LOCAL command; Connection was established by proxy itself (e.g. health-check)
+    if command == 0 {
+        return Ok(ProxyHeader::default());
+    }
+    if command != 1 {
+        return Err(Error::new("Unsupported PROXY v2 command"));
+    }
+
+    let (addresses, tlvs) = match header[13] >> 4 {
+        // AF_INET
+        1 if payload.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::from(Ipv4Addr::new(
+                    payload[at],
+                    payload[at + 1],
+                    payload[at + 2],
+                    payload[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+
+            (
+                Some((
+                    SocketAddr::new(ip(0), port(8)),
+                    SocketAddr::new(ip(4), port(10)),
+                )),
+                &payload[12..],
+            )
+        }
+        // AF_INET6
+        2 if payload.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&payload[at..at + 16]);
+                IpAddr::from(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+
+            (
+                Some((
+                    SocketAddr::new(ip(0), port(32)),
+                    SocketAddr::new(ip(16), port(34)),
+                )),
+                &payload[36..],
+            )
+        }
+        // AF_UNIX
+        3 if payload.len() >= 216 => (None, &payload[216..]),
+        // AF_UNSPEC
+        0 => (None, payload),
+        _ => return Err(Error::new("Malformed PROXY v2 addresses")),
+    };
+
+    Ok(ProxyHeader {
+        source: addresses.map(|(source, _)| source),
+        destination: addresses.map(|(_, destination)| destination),
+        tls: parse_tls(tlvs)?,
+    })
+}
+
+fn parse_tlvs(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, Error> {
+    let mut tlvs = vec![];
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(Error::new("Malformed PROXY v2 TLV"));
+        }
+
+        let length = u16::from_be_bytes([data[1], data[2]]) as usize;
+        if data.len() < 3 + length {
+            return Err(Error::new("Malformed PROXY v2 TLV"));
+        }
+
+        tlvs.push((data[0], &data[3..3 + length]));
+        data = &data[3 + length..];
+    }
+
+    Ok(tlvs)
+}
+
+fn parse_tls(data: &[u8]) -> Result<Option<TlsInfo>, Error> {
+    let mut tls = TlsInfo::default();
+    let mut secured = false;
+
+    for (kind, value) in parse_tlvs(data)? {
+        match kind {
+            PP2_TYPE_ALPN => tls.alpn_protocol = Some(value.to_vec()),
+            PP2_TYPE_AUTHORITY => {
+                tls.server_name = Some(String::from_utf8_lossy(value).into_owned())
+            }
+            // client (u8), verify (u32), sub-TLVs...
+            PP2_TYPE_SSL if value.len() >= 5 => {
+                secured = value[0] & PP2_CLIENT_SSL != 0;
+                for (kind, value) in parse_tlvs(&value[5..])? {
+                    if kind == PP2_SUBTYPE_SSL_VERSION {
+                        tls.protocol_version = Some(String::from_utf8_lossy(value).into_owned());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(if secured { Some(tls) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(family: u8, addresses: &[u8], tlvs: &[u8]) -> Vec<u8> {
+        let length = (addresses.len() + tlvs.len()) as u16;
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.push(0x21);
+        data.push(family);
+        data.extend_from_slice(&length.to_be_bytes());
+        data.extend_from_slice(addresses);
+        data.extend_from_slice(tlvs);
+        data
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nPRI * HTTP/2.0";
+        let header = read_header(&mut stream).await.unwrap();
+
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("198.51.100.1:443".parse().unwrap())
+        );
+        assert_eq!(stream, b"PRI * HTTP/2.0");
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        let header = read_header(&mut stream).await.unwrap();
+
+        assert_eq!(header, ProxyHeader::default());
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 ::1 56324 443\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_with_tls() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        let tlvs = [
+            &[PP2_TYPE_AUTHORITY, 0, 11][..],
+            b"example.com",
+            &[PP2_TYPE_SSL, 0, 13, PP2_CLIENT_SSL, 0, 0, 0, 0],
+            &[PP2_SUBTYPE_SSL_VERSION, 0, 5],
+            b"TLSv1",
+        ]
+        .concat();
+
+        let mut data = v2(0x11, &addresses, &tlvs);
+        data.extend_from_slice(b"PRI");
+        let mut stream = data.as_slice();
+        let header = read_header(&mut stream).await.unwrap();
+
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(
+            header.destination,
+            Some("198.51.100.1:443".parse().unwrap())
+        );
+
+        let tls = header.tls.unwrap();
+        assert_eq!(tls.server_name.as_deref(), Some("example.com"));
+        assert_eq!(tls.protocol_version.as_deref(), Some("TLSv1"));
+        assert_eq!(stream, b"PRI");
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_tlv() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        let data = v2(0x11, &addresses, &[PP2_TYPE_ALPN, 0, 8, b'h']);
+        assert!(read_header(&mut data.as_slice()).await.is_err());
+    }
+}
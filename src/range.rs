@@ -0,0 +1,548 @@
+//!
+//! A module that handles byte range requests ([RFC 9110](https://www.rfc-editor.org/rfc/rfc9110#section-14))
+//!
+//! Routes serve ranges of their representation with [respond].
+//! It evaluates `Range` and `If-Range` headers of request, and answers with
+//! `206 Partial Content` for a single range, `multipart/byteranges` for multiple ranges,
+//! or `416 Range Not Satisfiable` if none of ranges can be satisfied.
+//!
+//! # Examples
+//!
+//! ```
+//! use humus_terra::body::Body;
+//! use humus_terra::range::{respond, RangeSource};
+//! use humus_terra::response::ResponseBuilder;
+//! use hyper::{Request, Response};
+//! use hyper::header::CONTENT_TYPE;
+//!
+//! async fn video(request: Request<Body>) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+//!     let file = tokio::fs::File::open("video.mp4").await?;
+//!     let length = file.metadata().await?.len();
+//!
+//!     let builder = ResponseBuilder::from_request(&request).header(CONTENT_TYPE, "video/mp4");
+//!     Ok(respond(&request, builder, RangeSource::reader(file, length))?)
+//! }
+//! ```
+//!
+
+use crate::body::Body;
+use futures_util::stream;
+use headers::{ETag, HeaderMapExt, IfRange, LastModified};
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+};
+use hyper::{http, HeaderMap, Method, Request, Response, StatusCode};
+use rand::random;
+use std::collections::VecDeque;
+use std::io;
+use std::io::SeekFrom;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+static MAX_RANGES: usize = 16;
+static CHUNK_SIZE: u64 = 64 * 1024;
+
+///
+/// A seekable reader that ranges can be served from
+///
+pub trait SeekableReader: AsyncRead + AsyncSeek + Send + Unpin {}
+
+impl<R: AsyncRead + AsyncSeek + Send + Unpin> SeekableReader for R {}
+
+enum Kind {
+    Bytes(Bytes),
+    Reader(Box<dyn SeekableReader>, u64),
+}
+
+///
+/// A representation that ranges are served from
+///
+pub struct RangeSource {
+    kind: Kind,
+}
+
+impl RangeSource {
+    ///
+    /// Create new source from seekable reader with given length;
+    /// Reader must be positioned anywhere, as it's seeked before reading
+    ///
+    pub fn reader<R>(reader: R, length: u64) -> Self
+    where
+        R: SeekableReader + 'static,
+    {
+        Self {
+            kind: Kind::Reader(Box::new(reader), length),
+        }
+    }
+
+    ///
+    /// Get length of representation
+    ///
+    pub fn len(&self) -> u64 {
+        match &self.kind {
+            Kind::Bytes(bytes) => bytes.len() as u64,
+            Kind::Reader(_, length) => *length,
+        }
+    }
+
+    ///
+    /// Get whether representation is empty
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn into_body(self, segments: Vec<Segment>) -> Body {
+        match self.kind {
+            Kind::Reader(reader, _) => reader_body(reader, segments),
+            Kind::Bytes(bytes) => {
+                let mut buffer = Vec::new();
+                for segment in segments {
+                    match segment {
+                        Segment::Bytes(segment) => buffer.extend_from_slice(&segment),
+                        Segment::Range(start, end) => {
+                            buffer.extend_from_slice(&bytes[start as usize..=end as usize])
+                        }
+                    }
+                }
+                Body::from(buffer)
+            }
+        }
+    }
+
+    fn into_range_body(self, (start, end): (u64, u64)) -> Body {
+        match self.kind {
+            Kind::Bytes(bytes) => Body::from(bytes.slice(start as usize..=end as usize)),
+            Kind::Reader(reader, _) => reader_body(reader, vec![Segment::Range(start, end)]),
+        }
+    }
+
+    fn into_full_body(self) -> Body {
+        match self.kind {
+            Kind::Bytes(bytes) => Body::from(bytes),
+            Kind::Reader(_, 0) => Body::empty(),
+            Kind::Reader(reader, length) => {
+                reader_body(reader, vec![Segment::Range(0, length - 1)])
+            }
+        }
+    }
+}
+
+impl From<Bytes> for RangeSource {
+    fn from(bytes: Bytes) -> Self {
+        Self {
+            kind: Kind::Bytes(bytes),
+        }
+    }
+}
+
+impl From<Vec<u8>> for RangeSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::from(Bytes::from(bytes))
+    }
+}
+
+impl From<&'static [u8]> for RangeSource {
+    fn from(bytes: &'static [u8]) -> Self {
+        Self::from(Bytes::from_static(bytes))
+    }
+}
+
+///
+/// Build response serving given source with ranges requested by request.
+///
+/// `builder` is for `200 OK` response of whole representation;
+/// Its `ETag` and `Last-Modified` headers are used to evaluate `If-Range`,
+/// and its `Content-Type` header is used for parts of `multipart/byteranges`.
+/// Ranges are only served for `GET` and `HEAD` requests.
+///
+pub fn respond<B>(
+    request: &Request<B>,
+    builder: http::response::Builder,
+    source: RangeSource,
+) -> http::Result<Response<Body>> {
+    let length = source.len();
+    let is_head = request.method() == Method::HEAD;
+
+    let headers = builder.headers_ref();
+    let etag = headers.and_then(|headers| headers.typed_get::<ETag>());
+    let last_modified = headers
+        .and_then(|headers| headers.typed_get::<LastModified>())
+        .map(SystemTime::from);
+    let content_type = headers
+        .and_then(|headers| headers.get(CONTENT_TYPE))
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(str::to_string);
+
+    let ranges = if request.method() == Method::GET || is_head {
+        evaluate(request.headers(), length, etag.as_ref(), last_modified)
+    } else {
+        Ranges::Full
+    };
+
+    let builder = builder.header(ACCEPT_RANGES, "bytes");
+    let (builder, body) = match ranges {
+        Ranges::Full => (
+            builder
+                .status(StatusCode::OK)
+                .header(CONTENT_LENGTH, length),
+            (!is_head).then(|| source.into_full_body()),
+        ),
+        Ranges::Unsatisfiable => (
+            builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, unsatisfied_content_range(length)),
+            None,
+        ),
+        Ranges::Partial(ranges) if ranges.len() == 1 => (
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, content_range(ranges[0], length))
+                .header(CONTENT_LENGTH, ranges[0].1 - ranges[0].0 + 1),
+            (!is_head).then(|| source.into_range_body(ranges[0])),
+        ),
+        Ranges::Partial(ranges) => {
+            let multipart = Multipart::new(&ranges, length, content_type.as_deref());
+
+            let mut builder = builder;
+            if let Some(headers) = builder.headers_mut() {
+                headers.insert(CONTENT_TYPE, multipart.content_type());
+            }
+            let builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_LENGTH, multipart.len());
+
+            (
+                builder,
+                (!is_head).then(|| source.into_body(multipart.segments)),
+            )
+        }
+    };
+
+    builder.body(body.unwrap_or_default())
+}
+
+///
+/// Outcome of evaluating `Range` header against representation
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Ranges {
+    /// Serve whole representation
+    Full,
+
+    /// Serve given ranges; Inclusive, sorted and non-overlapping
+    Partial(Vec<(u64, u64)>),
+
+    /// None of requested ranges can be satisfied
+    Unsatisfiable,
+}
+
+///
+/// Evaluate `Range` and `If-Range` headers for representation of given length
+///
+fn evaluate(
+    headers: &HeaderMap,
+    length: u64,
+    etag: Option<&ETag>,
+    last_modified: Option<SystemTime>,
+) -> Ranges {
+    let range = match headers.get(RANGE).and_then(|range| range.to_str().ok()) {
+        None => return Ranges::Full,
+        Some(range) => range,
+    };
+
+    if let Some(if_range) = headers.typed_get::<IfRange>() {
+        let last_modified = last_modified.map(LastModified::from);
+        if if_range.is_modified(etag, last_modified.as_ref()) {
+            return Ranges::Full;
+        }
+    }
+
+    parse(range, length)
+}
+
+fn parse(range: &str, length: u64) -> Ranges {
+    let specs = match range.trim().strip_prefix("bytes=") {
+        // Unknown range units are ignored
+        None => return Ranges::Full,
+        Some(specs) => specs,
+    };
+
+    let mut ranges = vec![];
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let (start, end) = match spec.split_once('-') {
+            None => return Ranges::Full,
+            Some(bounds) => bounds,
+        };
+
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return Ranges::Full,
+            // Suffix; last N bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Err(_) => return Ranges::Full,
+                Ok(0) => None,
+                Ok(suffix) => (length > 0).then(|| (length.saturating_sub(suffix), length - 1)),
+            },
+            (start, end) => {
+                let start = match start.parse::<u64>() {
+                    Err(_) => return Ranges::Full,
+                    Ok(start) => start,
+                };
+                let end = match end {
+                    "" => u64::MAX,
+                    end => match end.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return Ranges::Full,
+                    },
+                };
+
+                (start < length).then(|| (start, end.min(length - 1)))
+            }
+        };
+
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    // Coalesce overlapping or adjacent ranges
+    ranges.sort();
+    let mut coalesced: Vec<(u64, u64)> = vec![];
+    for (start, end) in ranges {
+        match coalesced.last_mut() {
+            Some((_, last)) if start <= last.saturating_add(1) => *last = (*last).max(end),
+            _ => coalesced.push((start, end)),
+        }
+    }
+
+    if coalesced.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+
+    Ranges::Partial(coalesced)
+}
+
+///
+/// Get value of `Content-Range` header for given range
+///
+fn content_range((start, end): (u64, u64), length: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, length)).unwrap()
+}
+
+///
+/// Get value of `Content-Range` header for unsatisfiable request
+///
+fn unsatisfied_content_range(length: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("bytes */{}", length)).unwrap()
+}
+
+#[derive(Clone)]
+enum Segment {
+    Bytes(Bytes),
+    Range(u64, u64),
+}
+
+///
+/// A plan of `multipart/byteranges` body
+///
+struct Multipart {
+    boundary: String,
+    segments: Vec<Segment>,
+}
+
+impl Multipart {
+    fn new(ranges: &[(u64, u64)], length: u64, content_type: Option<&str>) -> Self {
+        let boundary = format!("{:032x}", random::<u128>());
+
+        let mut segments = vec![];
+        for (i, range) in ranges.iter().enumerate() {
+            let mut header = String::new();
+            if i > 0 {
+                header.push_str("\r\n");
+            }
+            header.push_str(&format!("--{}\r\n", boundary));
+            if let Some(content_type) = content_type {
+                header.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+            header.push_str(&format!(
+                "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                range.0, range.1, length
+            ));
+
+            segments.push(Segment::Bytes(Bytes::from(header)));
+            segments.push(Segment::Range(range.0, range.1));
+        }
+        segments.push(Segment::Bytes(Bytes::from(format!(
+            "\r\n--{}--\r\n",
+            boundary
+        ))));
+
+        Self { boundary, segments }
+    }
+
+    ///
+    /// Get value of `Content-Type` header of multipart body
+    ///
+    fn content_type(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", self.boundary)).unwrap()
+    }
+
+    ///
+    /// Get length of multipart body
+    ///
+    fn len(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Bytes(bytes) => bytes.len() as u64,
+                Segment::Range(start, end) => end - start + 1,
+            })
+            .sum()
+    }
+}
+
+fn reader_body(reader: Box<dyn SeekableReader>, segments: Vec<Segment>) -> Body {
+    struct State {
+        reader: Box<dyn SeekableReader>,
+        segments: VecDeque<Segment>,
+        // Remaining bytes of current range
+        remaining: Option<u64>,
+    }
+
+    let state = State {
+        reader,
+        segments: VecDeque::from(segments),
+        remaining: None,
+    };
+
+    let stream = stream::try_unfold(state, |mut state| async move {
+        loop {
+            if let Some(remaining) = state.remaining.filter(|remaining| *remaining > 0) {
+                let size = remaining.min(CHUNK_SIZE) as usize;
+                let mut chunk = vec![0u8; size];
+                state.reader.read_exact(&mut chunk).await?;
+
+                state.remaining = Some(remaining - size as u64);
+                return Ok::<_, io::Error>(Some((Bytes::from(chunk), state)));
+            }
+
+            match state.segments.pop_front() {
+                None => return Ok(None),
+                Some(Segment::Bytes(bytes)) => return Ok(Some((bytes, state))),
+                Some(Segment::Range(start, end)) => {
+                    state.reader.seek(SeekFrom::Start(start)).await?;
+                    state.remaining = Some(end - start + 1);
+                }
+            }
+        }
+    });
+
+    Body::from_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{ETAG, IF_RANGE};
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(parse("bytes=0-9", 100), Ranges::Partial(vec![(0, 9)]));
+        assert_eq!(parse("bytes=90-", 100), Ranges::Partial(vec![(90, 99)]));
+        assert_eq!(parse("bytes=-10", 100), Ranges::Partial(vec![(90, 99)]));
+        assert_eq!(parse("bytes=-200", 100), Ranges::Partial(vec![(0, 99)]));
+        assert_eq!(parse("bytes=50-200", 100), Ranges::Partial(vec![(50, 99)]));
+        assert_eq!(
+            parse("bytes=0-4, 20-29, 3-9", 100),
+            Ranges::Partial(vec![(0, 9), (20, 29)])
+        );
+        assert_eq!(parse("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=9-0", 100), Ranges::Full);
+        assert_eq!(parse("items=0-9", 100), Ranges::Full);
+    }
+
+    #[tokio::test]
+    async fn multipart() {
+        let data = (0u8..100).collect::<Vec<u8>>();
+        let multipart = Multipart::new(&[(0, 1), (98, 99)], 100, Some("text/plain"));
+        let boundary = multipart.boundary.clone();
+
+        let expected = [
+            format!(
+                "--{}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/100\r\n\r\n",
+                boundary
+            )
+            .as_bytes(),
+            &[0, 1],
+            format!(
+                "\r\n--{}\r\nContent-Type: text/plain\r\nContent-Range: bytes 98-99/100\r\n\r\n",
+                boundary
+            )
+            .as_bytes(),
+            &[98, 99],
+            format!("\r\n--{}--\r\n", boundary).as_bytes(),
+        ]
+        .concat();
+        assert_eq!(expected.len() as u64, multipart.len());
+
+        let sources = [
+            RangeSource::reader(io::Cursor::new(data.clone()), 100),
+            RangeSource::from(data),
+        ];
+        for source in sources {
+            let body = source.into_body(multipart.segments.clone());
+            let body = body.to_bytes().await.unwrap();
+            assert_eq!(body.as_ref(), expected.as_slice());
+        }
+    }
+
+    #[tokio::test]
+    async fn respond_ranges() {
+        let request = |range: Option<&str>, if_range: Option<&str>| {
+            let mut builder = Request::builder();
+            if let Some(range) = range {
+                builder = builder.header(RANGE, range);
+            }
+            if let Some(if_range) = if_range {
+                builder = builder.header(IF_RANGE, if_range);
+            }
+            builder.body(()).unwrap()
+        };
+        let builder = || Response::builder().header(ETAG, "\"v1\"");
+        let source = || RangeSource::from(&b"hello, world"[..]);
+
+        let response = respond(&request(None, None), builder(), source()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "12");
+
+        let response = respond(&request(Some("bytes=-5"), None), builder(), source()).unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-11/12");
+        let body = response.into_body().to_bytes().await.unwrap();
+        assert_eq!(body.as_ref(), b"world");
+
+        let response = respond(&request(Some("bytes=0-1,5-6"), None), builder(), source()).unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("multipart/byteranges; boundary="));
+
+        let response = respond(&request(Some("bytes=20-"), None), builder(), source()).unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */12");
+
+        let if_range = Some("\"v0\"");
+        let response = respond(&request(Some("bytes=-5"), if_range), builder(), source()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
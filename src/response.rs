@@ -1,16 +1,28 @@
 //!
 //! A module that provides abstraction and management of responses
 //!
+//! Configuration for responses is resolved in the following order:
+//!
+//! 1. The deepest route that overrides [crate::route::Route::response_config]
+//! 2. The application; See [crate::App::with_response_config]
+//! 3. Process-wide configuration; See [ResponseConfig::set]
+//!
 
+use crate::compression::Compression;
+use crate::conditional::AutoETag;
+use crate::cors::Cors;
+use crate::security::SecurityHeaders;
+use arc_swap::ArcSwap;
 use hyper::header::{
-    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, VARY,
 };
-use hyper::{http, Response};
+use hyper::{http, HeaderMap, Request, Response};
 use lazy_static::lazy_static;
-use tokio::sync::RwLock;
+use std::sync::Arc;
 
 lazy_static! {
-    static ref CONFIG: RwLock<ResponseConfig> = RwLock::new(ResponseConfig::new());
+    static ref CONFIG: ArcSwap<ResponseConfig> = ArcSwap::from_pointee(ResponseConfig::new());
 }
 
 ///
@@ -26,6 +38,19 @@ pub struct ResponseConfig {
 
     /// [Access-Control-Allow-Headers](https://fetch.spec.whatwg.org/#http-access-control-allow-headers)
     pub access_control_allow_headers: Option<String>,
+
+    /// Origin-aware CORS, including automatic preflight handling.
+    /// This takes precedence over static `access_control_*` options above
+    pub cors: Option<Cors>,
+
+    /// Preset of security-related headers applied to every response
+    pub security_headers: Option<SecurityHeaders>,
+
+    /// Compression of responses negotiated via `Accept-Encoding`
+    pub compression: Option<Compression>,
+
+    /// `ETag` computed from content of buffered responses, with conditional `GET` handling
+    pub auto_etag: Option<AutoETag>,
 }
 
 impl ResponseConfig {
@@ -37,14 +62,44 @@ impl ResponseConfig {
             access_control_allow_origin: None,
             access_control_allow_methods: None,
             access_control_allow_headers: None,
+            cors: None,
+            security_headers: None,
+            compression: None,
+            auto_etag: None,
         }
     }
 
     ///
-    /// Override configuration by given argument
+    /// Override process-wide configuration by given argument
+    ///
+    /// Process-wide configuration applies to applications and routes
+    /// that don't have their own configuration.
+    /// Responses being built keep using the configuration they started with.
     ///
     pub async fn set(this: Self) {
-        *CONFIG.write().await = this;
+        CONFIG.store(Arc::new(this));
+    }
+
+    pub(crate) fn global() -> Arc<Self> {
+        CONFIG.load_full()
+    }
+
+    ///
+    /// Retrieve configuration which applies to given request
+    ///
+    /// Returns `None` if the request didn't come through [crate::App].
+    ///
+    pub fn from_request<B>(request: &Request<B>) -> Option<&Self> {
+        request
+            .extensions()
+            .get::<Arc<Self>>()
+            .map(|config| config.as_ref())
+    }
+}
+
+impl Default for ResponseConfig {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -58,10 +113,32 @@ impl ResponseBuilder {
     /// Create new builder for response with stored options.
     /// For options, See [ResponseConfig]
     ///
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> http::response::Builder {
-        let mut builder = Response::builder();
+        Self::with_config(&CONFIG.load())
+    }
 
-        let config = CONFIG.blocking_read().clone();
+    ///
+    /// Create new builder for response with configuration which applies to given request.
+    /// See [ResponseConfig::from_request]
+    ///
+    pub fn from_request<B>(request: &Request<B>) -> http::response::Builder {
+        match ResponseConfig::from_request(request) {
+            None => Self::new(),
+            Some(config) => Self::with_config(config),
+        }
+    }
+
+    ///
+    /// Create new builder for response with given configuration
+    ///
+    /// Static `access_control_*` options are ignored if [ResponseConfig::cors] is set.
+    ///
+    pub fn with_config(config: &ResponseConfig) -> http::response::Builder {
+        let mut builder = Response::builder();
+        if config.cors.is_some() {
+            return builder;
+        }
 
         if let Some(cors_origin) = config.access_control_allow_origin.as_ref() {
             builder = builder.header(ACCESS_CONTROL_ALLOW_ORIGIN, cors_origin);
@@ -76,3 +153,36 @@ impl ResponseBuilder {
         builder
     }
 }
+
+///
+/// Add given header name to `Vary` header, unless it's already listed
+///
+pub(crate) fn append_vary(headers: &mut HeaderMap, name: HeaderName) {
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| {
+            let value = value.trim();
+            value == "*" || value.eq_ignore_ascii_case(name.as_str())
+        });
+
+    if !listed {
+        headers.append(VARY, HeaderValue::from(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn builder_in_runtime() {
+        let response = ResponseBuilder::new().body(()).unwrap();
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_HEADERS)
+            .is_none());
+    }
+}
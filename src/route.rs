@@ -2,9 +2,9 @@
 //! A module that provides abstraction for routes and its helpers
 //!
 
+use crate::body::Body;
+use crate::response::ResponseConfig;
 use async_trait::async_trait;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
 use hyper::{Request, Response};
 use std::sync::Arc;
 
@@ -24,6 +24,20 @@ pub trait Route {
         vec![]
     }
 
+    /// Get configuration for responses of this route and its descendants
+    ///
+    /// Returns `None` to inherit configuration from ancestors
+    fn response_config(&self) -> Option<Arc<ResponseConfig>> {
+        None
+    }
+
+    /// Get whether this route handles every path below it that none of its children match
+    ///
+    /// The unmatched part of path is available with [Subpath::from_request]
+    fn is_catch_all(&self) -> bool {
+        false
+    }
+
     /// Configure route and Initialise its resources
     async fn configure(&self) -> Result<(), Box<Error>> {
         Ok(())
@@ -35,8 +49,46 @@ pub trait Route {
     }
 
     /// Handle request asynchronously
-    async fn handle(&self, request: Request<Incoming>)
-        -> Result<Response<Full<Bytes>>, Box<Error>>;
+    async fn handle(&self, request: Request<Body>) -> Result<Response<Body>, Box<Error>>;
+}
+
+///
+/// Part of request path below the catch-all route that handles it
+///
+/// Segments are joined with `/`, without leading slash and empty segments.
+/// They're still percent-encoded as they appeared in the request.
+/// See [Route::is_catch_all].
+///
+#[derive(Clone, Debug, Default)]
+pub struct Subpath(String);
+
+impl Subpath {
+    pub(crate) fn new(subpath: &str) -> Self {
+        Self(subpath.to_string())
+    }
+
+    ///
+    /// Retrieve subpath of given request
+    ///
+    /// Returns `None` if the request didn't come through [crate::App].
+    ///
+    pub fn from_request<B>(request: &Request<B>) -> Option<&Self> {
+        request.extensions().get::<Self>()
+    }
+
+    ///
+    /// Get subpath as string
+    ///
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    ///
+    /// Get segments of subpath
+    ///
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/').filter(|segment| !segment.is_empty())
+    }
 }
 
 pub(crate) async fn configure_all(root: Arc<dyn Route + Send + Sync>) -> Result<(), Box<Error>> {
@@ -57,21 +109,30 @@ pub(crate) async fn shutdown_all(root: Arc<dyn Route + Send + Sync>) -> Result<(
     Ok(())
 }
 
-pub(crate) fn match_route(
-    path: &str,
-    root: Arc<dyn Route + Send + Sync>,
-) -> Option<Arc<dyn Route + Send + Sync>> {
-    let parts = path.split("/").skip(1).collect::<Vec<&str>>();
+pub(crate) struct RouteMatch {
+    /// Matched route; `None` if there's no route for the path
+    pub route: Option<Arc<dyn Route + Send + Sync>>,
 
-    let mut current = root;
-    for part in parts {
-        if part.len() == 0 {
-            continue;
-        }
+    /// Configuration of the deepest route on the path that overrides it
+    pub response_config: Option<Arc<ResponseConfig>>,
+
+    /// Unmatched part of the path, handled by catch-all route
+    pub subpath: Subpath,
+}
 
+pub(crate) fn resolve_route(path: &str, root: Arc<dyn Route + Send + Sync>) -> RouteMatch {
+    let parts = path
+        .split("/")
+        .skip(1)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>();
+
+    let mut response_config = root.response_config();
+    let mut current = root;
+    for (i, part) in parts.iter().enumerate() {
         let mut found = false;
         for route in current.children() {
-            if route.name() == part {
+            if route.name() == *part {
                 current = route;
                 found = true;
                 break;
@@ -79,11 +140,24 @@ pub(crate) fn match_route(
         }
 
         if !found {
-            return None;
+            let route = current.is_catch_all().then_some(current);
+            return RouteMatch {
+                route,
+                response_config,
+                subpath: Subpath::new(&parts[i..].join("/")),
+            };
+        }
+
+        if let Some(config) = current.response_config() {
+            response_config = Some(config);
         }
     }
 
-    Some(current)
+    RouteMatch {
+        route: Some(current),
+        response_config,
+        subpath: Subpath::default(),
+    }
 }
 
 #[cfg(test)]
@@ -132,10 +206,7 @@ mod tests {
             vec![self.route_a.clone()]
         }
 
-        async fn handle(
-            &self,
-            _request: Request<Incoming>,
-        ) -> Result<Response<Full<Bytes>>, Box<Error>> {
+        async fn handle(&self, _request: Request<Body>) -> Result<Response<Body>, Box<Error>> {
             panic!()
         }
     }
@@ -146,14 +217,17 @@ mod tests {
             "a"
         }
 
+        fn response_config(&self) -> Option<Arc<ResponseConfig>> {
+            let mut config = ResponseConfig::new();
+            config.access_control_allow_origin = Some("a".to_string());
+            Some(Arc::new(config))
+        }
+
         fn children(&self) -> Vec<Arc<dyn Route + Send + Sync>> {
             vec![self.route_b.clone()]
         }
 
-        async fn handle(
-            &self,
-            _request: Request<Incoming>,
-        ) -> Result<Response<Full<Bytes>>, Box<Error>> {
+        async fn handle(&self, _request: Request<Body>) -> Result<Response<Body>, Box<Error>> {
             panic!()
         }
     }
@@ -164,10 +238,11 @@ mod tests {
             "b"
         }
 
-        async fn handle(
-            &self,
-            _request: Request<Incoming>,
-        ) -> Result<Response<Full<Bytes>>, Box<Error>> {
+        fn is_catch_all(&self) -> bool {
+            true
+        }
+
+        async fn handle(&self, _request: Request<Body>) -> Result<Response<Body>, Box<Error>> {
             panic!()
         }
     }
@@ -175,7 +250,7 @@ mod tests {
     #[test]
     fn route_root() {
         let root = Arc::new(RootRoute::new());
-        match match_route("/", root) {
+        match resolve_route("/", root).route {
             None => panic!("Couldn't find route for '/'"),
             Some(route) => assert_eq!(route.name(), ""),
         };
@@ -184,7 +259,7 @@ mod tests {
     #[test]
     fn route_a() {
         let root = Arc::new(RootRoute::new());
-        match match_route("/a/", root) {
+        match resolve_route("/a/", root).route {
             None => panic!("Couldn't find route for '/a/'"),
             Some(route) => assert_eq!(route.name(), "a"),
         };
@@ -193,9 +268,35 @@ mod tests {
     #[test]
     fn route_b() {
         let root = Arc::new(RootRoute::new());
-        match match_route("/a/b", root) {
+        match resolve_route("/a/b", root).route {
             None => panic!("Couldn't find route for '/b'"),
             Some(route) => assert_eq!(route.name(), "b"),
         };
     }
+
+    #[test]
+    fn route_catch_all() {
+        let root = Arc::new(RootRoute::new());
+        let matched = resolve_route("/a/b/c//d", root.clone());
+        match matched.route {
+            None => panic!("Couldn't find route for '/a/b/c//d'"),
+            Some(route) => assert_eq!(route.name(), "b"),
+        };
+        assert_eq!(matched.subpath.as_str(), "c/d");
+
+        assert!(resolve_route("/a/c", root).route.is_none());
+    }
+
+    #[test]
+    fn route_response_config() {
+        let origin = |path: &str| {
+            resolve_route(path, Arc::new(RootRoute::new()))
+                .response_config
+                .and_then(|config| config.access_control_allow_origin.clone())
+        };
+
+        assert_eq!(origin("/"), None);
+        assert_eq!(origin("/a/b"), Some("a".to_string()));
+        assert_eq!(origin("/a/unknown"), Some("a".to_string()));
+    }
 }
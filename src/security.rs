@@ -0,0 +1,256 @@
+//!
+//! A module that provides preset of security-related response headers
+//!
+//! Assign [SecurityHeaders] to [crate::response::ResponseConfig::security_headers]
+//! to stamp headers onto every response of an application or a route subtree.
+//! Headers already set by routes are left untouched.
+//!
+
+use base64::Engine;
+use hyper::header::{
+    HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use hyper::{Request, Response};
+use rand::random;
+
+static PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+static CROSS_ORIGIN_OPENER_POLICY: HeaderName =
+    HeaderName::from_static("cross-origin-opener-policy");
+static CROSS_ORIGIN_EMBEDDER_POLICY: HeaderName =
+    HeaderName::from_static("cross-origin-embedder-policy");
+static CROSS_ORIGIN_RESOURCE_POLICY: HeaderName =
+    HeaderName::from_static("cross-origin-resource-policy");
+
+static NONCE_PLACEHOLDER: &str = "{nonce}";
+
+///
+/// A nonce generated for `Content-Security-Policy` of each request
+///
+/// Generated only if the policy contains `{nonce}` placeholder.
+/// Routes can embed it into HTML, e.g. `<script nonce="...">`.
+///
+#[derive(Clone, Debug)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    fn generate() -> Self {
+        Self(base64::prelude::BASE64_STANDARD.encode(random::<[u8; 16]>()))
+    }
+
+    ///
+    /// Retrieve nonce of given request
+    ///
+    /// Returns `None` if the policy applied to request doesn't use nonce.
+    ///
+    pub fn from_request<B>(request: &Request<B>) -> Option<&Self> {
+        request.extensions().get::<Self>()
+    }
+
+    ///
+    /// Get nonce as string
+    ///
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+///
+/// A preset of security-related response headers
+///
+/// Every setter takes raw header value; Pass `None` to omit the header.
+///
+/// # Panics
+///
+/// Setters panic if given value isn't a valid header value, e.g. contains line breaks,
+/// so a mistyped policy is caught on configuration instead of being dropped silently.
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::security::SecurityHeaders;
+///
+/// let headers = SecurityHeaders::new()
+///     .content_security_policy(Some("default-src 'self'; script-src 'nonce-{nonce}'"))
+///     .permissions_policy(Some("camera=(), microphone=()"));
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecurityHeaders {
+    ///
+    /// Create new preset with recommended values
+    ///
+    /// - `Strict-Transport-Security: max-age=63072000; includeSubDomains`
+    /// - `X-Content-Type-Options: nosniff`
+    /// - `X-Frame-Options: DENY`
+    /// - `Referrer-Policy: strict-origin-when-cross-origin`
+    /// - `Cross-Origin-Opener-Policy: same-origin`
+    /// - `Cross-Origin-Resource-Policy: same-origin`
+    ///
+    /// `Content-Security-Policy`, `Permissions-Policy` and `Cross-Origin-Embedder-Policy`
+    /// depend on the application, so they're not set by default.
+    ///
+    pub fn new() -> Self {
+        Self { headers: vec![] }
+            .strict_transport_security(Some("max-age=63072000; includeSubDomains"))
+            .content_type_options(Some("nosniff"))
+            .frame_options(Some("DENY"))
+            .referrer_policy(Some("strict-origin-when-cross-origin"))
+            .cross_origin_opener_policy(Some("same-origin"))
+            .cross_origin_resource_policy(Some("same-origin"))
+    }
+
+    fn set(mut self, name: &HeaderName, value: Option<&str>) -> Self {
+        self.headers.retain(|(header, _)| header != name);
+        if let Some(value) = value {
+            let value = HeaderValue::from_str(value)
+                .unwrap_or_else(|_| panic!("Invalid value of {}: {:?}", name, value));
+            self.headers.push((name.clone(), value));
+        }
+        self
+    }
+
+    /// Set [Strict-Transport-Security](https://developer.mozilla.org/docs/Web/HTTP/Headers/Strict-Transport-Security)
+    pub fn strict_transport_security(self, value: Option<&str>) -> Self {
+        self.set(&STRICT_TRANSPORT_SECURITY, value)
+    }
+
+    /// Set [X-Content-Type-Options](https://developer.mozilla.org/docs/Web/HTTP/Headers/X-Content-Type-Options)
+    pub fn content_type_options(self, value: Option<&str>) -> Self {
+        self.set(&X_CONTENT_TYPE_OPTIONS, value)
+    }
+
+    /// Set [X-Frame-Options](https://developer.mozilla.org/docs/Web/HTTP/Headers/X-Frame-Options)
+    pub fn frame_options(self, value: Option<&str>) -> Self {
+        self.set(&X_FRAME_OPTIONS, value)
+    }
+
+    /// Set [Referrer-Policy](https://developer.mozilla.org/docs/Web/HTTP/Headers/Referrer-Policy)
+    pub fn referrer_policy(self, value: Option<&str>) -> Self {
+        self.set(&REFERRER_POLICY, value)
+    }
+
+    /// Set [Content-Security-Policy](https://developer.mozilla.org/docs/Web/HTTP/Headers/Content-Security-Policy)
+    ///
+    /// Every `{nonce}` in the policy is replaced with nonce generated for each request.
+    /// See [CspNonce].
+    pub fn content_security_policy(self, value: Option<&str>) -> Self {
+        self.set(&CONTENT_SECURITY_POLICY, value)
+    }
+
+    /// Set [Permissions-Policy](https://developer.mozilla.org/docs/Web/HTTP/Headers/Permissions-Policy)
+    pub fn permissions_policy(self, value: Option<&str>) -> Self {
+        self.set(&PERMISSIONS_POLICY, value)
+    }
+
+    /// Set [Cross-Origin-Opener-Policy](https://developer.mozilla.org/docs/Web/HTTP/Headers/Cross-Origin-Opener-Policy)
+    pub fn cross_origin_opener_policy(self, value: Option<&str>) -> Self {
+        self.set(&CROSS_ORIGIN_OPENER_POLICY, value)
+    }
+
+    /// Set [Cross-Origin-Embedder-Policy](https://developer.mozilla.org/docs/Web/HTTP/Headers/Cross-Origin-Embedder-Policy)
+    pub fn cross_origin_embedder_policy(self, value: Option<&str>) -> Self {
+        self.set(&CROSS_ORIGIN_EMBEDDER_POLICY, value)
+    }
+
+    /// Set [Cross-Origin-Resource-Policy](https://developer.mozilla.org/docs/Web/HTTP/Headers/Cross-Origin-Resource-Policy)
+    pub fn cross_origin_resource_policy(self, value: Option<&str>) -> Self {
+        self.set(&CROSS_ORIGIN_RESOURCE_POLICY, value)
+    }
+
+    pub(crate) fn prepare<B>(&self, request: &mut Request<B>) -> Option<CspNonce> {
+        let uses_nonce = self.headers.iter().any(|(name, value)| {
+            name == CONTENT_SECURITY_POLICY
+                && value
+                    .to_str()
+                    .is_ok_and(|value| value.contains(NONCE_PLACEHOLDER))
+        });
+        if !uses_nonce {
+            return None;
+        }
+
+        let nonce = CspNonce::generate();
+        request.extensions_mut().insert(nonce.clone());
+        Some(nonce)
+    }
+
+    pub(crate) fn apply<B>(&self, nonce: Option<&CspNonce>, response: &mut Response<B>) {
+        let headers = response.headers_mut();
+        for (name, value) in &self.headers {
+            if headers.contains_key(name) {
+                continue;
+            }
+
+            let value = match nonce {
+                // Nonce is base64, so substituted policy stays valid
+                Some(nonce) if name == CONTENT_SECURITY_POLICY => value
+                    .to_str()
+                    .ok()
+                    .map(|value| value.replace(NONCE_PLACEHOLDER, nonce.as_str()))
+                    .and_then(|value| HeaderValue::from_str(&value).ok()),
+                _ => Some(value.clone()),
+            };
+            if let Some(value) = value {
+                headers.insert(name.clone(), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset() {
+        let headers = SecurityHeaders::new().frame_options(None);
+
+        let mut response = Response::builder()
+            .header(REFERRER_POLICY, "no-referrer")
+            .body(())
+            .unwrap();
+        headers.apply(None, &mut response);
+
+        let headers = response.headers();
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[REFERRER_POLICY], "no-referrer");
+        assert!(!headers.contains_key(X_FRAME_OPTIONS));
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+    }
+
+    #[test]
+    fn nonce() {
+        let headers =
+            SecurityHeaders::new().content_security_policy(Some("script-src 'nonce-{nonce}'"));
+
+        let mut request = Request::new(());
+        let nonce = headers.prepare(&mut request).unwrap();
+        assert_eq!(
+            CspNonce::from_request(&request).unwrap().as_str(),
+            nonce.as_str()
+        );
+
+        let mut response = Response::new(());
+        headers.apply(Some(&nonce), &mut response);
+        assert_eq!(
+            response.headers()[CONTENT_SECURITY_POLICY],
+            format!("script-src 'nonce-{}'", nonce.as_str()).as_str()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid value of content-security-policy")]
+    fn invalid_value() {
+        SecurityHeaders::new().content_security_policy(Some("default-src 'self'\r\nX-Injected: 1"));
+    }
+}
@@ -0,0 +1,374 @@
+//!
+//! A module that provides [Server-Sent Events](https://html.spec.whatwg.org/multipage/server-sent-events.html)
+//!
+//! [Sse] turns a stream of [Event] into a streamed `text/event-stream` response.
+//! Idle connections are kept alive with comments, and clients that reconnect
+//! tell which event they saw last with [last_event_id].
+//!
+//! When client disconnects, the response body, including the stream, is dropped.
+//! Producers using [Sse::channel] observe it with [EventSender::closed]
+//! or as [Disconnected] error on [EventSender::send].
+//!
+//! # Examples
+//!
+//! ```
+//! use humus_terra::body::Body;
+//! use humus_terra::sse::{Event, Sse};
+//! use hyper::{Request, Response};
+//!
+//! fn updates(request: Request<Body>) -> Response<Body> {
+//!     let (sender, sse) = Sse::channel(16);
+//!
+//!     tokio::spawn(async move {
+//!         let mut i = 0;
+//!         while sender.send(Event::new().id(&i.to_string()).data("tick")).await.is_ok() {
+//!             i += 1;
+//!             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+//!         }
+//!     });
+//!
+//!     sse.into_response(&request)
+//! }
+//! ```
+//!
+
+use crate::body::Body;
+use crate::compression::NoCompression;
+use crate::response::ResponseBuilder;
+use futures_util::{stream, Stream, StreamExt};
+use hyper::body::Bytes;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+
+///
+/// An event of event stream
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::sse::Event;
+/// use std::time::Duration;
+///
+/// let event = Event::new()
+///     .id("42")
+///     .event("update")
+///     .data("first line\nsecond line")
+///     .retry(Duration::from_secs(5));
+/// ```
+///
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    ///
+    /// Create new empty event
+    ///
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Set ID of event, which client sends back with `Last-Event-ID` on reconnection.
+    /// Line breaks and NUL are removed
+    ///
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.replace(['\r', '\n', '\0'], ""));
+        self
+    }
+
+    ///
+    /// Set type of event. Line breaks are removed
+    ///
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.replace(['\r', '\n'], ""));
+        self
+    }
+
+    ///
+    /// Set data of event; Multi-line data is sent as multiple `data` fields
+    ///
+    pub fn data(mut self, data: &str) -> Self {
+        self.data = Some(data.to_string());
+        self
+    }
+
+    ///
+    /// Set data of event to JSON representation of given value
+    ///
+    pub fn json_data<T: Serialize>(self, data: &T) -> Result<Self, serde_json::Error> {
+        Ok(self.data(&serde_json::to_string(data)?))
+    }
+
+    ///
+    /// Set time for client to wait before reconnecting
+    ///
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    ///
+    /// Set comment of event, which client ignores
+    ///
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        let mut buffer = String::new();
+
+        if let Some(comment) = &self.comment {
+            for line in lines(comment) {
+                buffer.push_str(&format!(":{}\n", line));
+            }
+        }
+        if let Some(event) = &self.event {
+            buffer.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(data) = &self.data {
+            for line in lines(data) {
+                buffer.push_str(&format!("data: {}\n", line));
+            }
+        }
+        if let Some(id) = &self.id {
+            buffer.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = &self.retry {
+            buffer.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        buffer.push('\n');
+
+        Bytes::from(buffer)
+    }
+}
+
+// Split by every line break of SSE; CRLF, lone CR and lone LF
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split("\r\n").flat_map(|line| line.split(['\r', '\n']))
+}
+
+///
+/// Get `Last-Event-ID` header of request; ID of the last event client received
+///
+pub fn last_event_id<B>(request: &Request<B>) -> Option<&str> {
+    request
+        .headers()
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+}
+
+///
+/// An error indicating that client of event stream disconnected
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl Display for Disconnected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "client of event stream disconnected")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+///
+/// A sending half of event stream created with [Sse::channel]
+///
+#[derive(Clone, Debug)]
+pub struct EventSender {
+    sender: mpsc::Sender<Event>,
+}
+
+impl EventSender {
+    ///
+    /// Send event, waiting for capacity of buffer.
+    /// Fails if client disconnected
+    ///
+    pub async fn send(&self, event: Event) -> Result<(), Disconnected> {
+        self.sender.send(event).await.map_err(|_| Disconnected)
+    }
+
+    ///
+    /// Wait until client disconnects
+    ///
+    pub async fn closed(&self) {
+        self.sender.closed().await
+    }
+
+    ///
+    /// Get whether client disconnected
+    ///
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+///
+/// A response of Server-Sent Events
+///
+pub struct Sse {
+    events: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<Duration>,
+}
+
+impl Sse {
+    ///
+    /// Create new event stream from stream of events,
+    /// which sends keep-alive comment after 15 seconds of idle
+    ///
+    pub fn new<S>(events: S) -> Self
+    where
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        Self {
+            events: Box::pin(events),
+            keep_alive: Some(Duration::from_secs(15)),
+        }
+    }
+
+    ///
+    /// Create new event stream fed by returned sender, buffering up to `capacity` events
+    ///
+    pub fn channel(capacity: usize) -> (EventSender, Self) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let events = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|event| (event, receiver))
+        });
+
+        (EventSender { sender }, Self::new(events))
+    }
+
+    ///
+    /// Set interval of keep-alive comments sent while stream is idle;
+    /// `None` to disable them
+    ///
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    ///
+    /// Create response streaming events with configuration which applies to given request
+    ///
+    pub fn into_response<B>(self, request: &Request<B>) -> Response<Body> {
+        let stream = EventStream {
+            events: self.events,
+            keep_alive: self
+                .keep_alive
+                .map(|interval| (interval, Box::pin(tokio::time::sleep(interval)))),
+        };
+
+        let mut response = ResponseBuilder::from_request(request)
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Body::from_stream(stream))
+            .unwrap();
+        // Compression would hold events back in encoder's buffer
+        response.extensions_mut().insert(NoCompression);
+        response
+    }
+}
+
+struct EventStream {
+    events: Pin<Box<dyn Stream<Item = Event> + Send>>,
+    keep_alive: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl Stream for EventStream {
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.events.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => {
+                if let Some((interval, sleep)) = &mut self.keep_alive {
+                    sleep.as_mut().reset(Instant::now() + *interval);
+                }
+                return Poll::Ready(Some(Ok(event.to_bytes())));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+
+        if let Some((interval, sleep)) = &mut self.keep_alive {
+            if sleep.as_mut().poll(cx).is_ready() {
+                sleep.as_mut().reset(Instant::now() + *interval);
+                // Register waker on the new deadline
+                let _ = sleep.as_mut().poll(cx);
+                return Poll::Ready(Some(Ok(Bytes::from_static(b":\n\n"))));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[test]
+    fn serialize() {
+        let event = Event::new()
+            .id("1\n2")
+            .event("update")
+            .data("a\nb\r\nc")
+            .retry(Duration::from_millis(1500));
+        assert_eq!(
+            event.to_bytes(),
+            "event: update\ndata: a\ndata: b\ndata: c\nid: 12\nretry: 1500\n\n"
+        );
+
+        assert_eq!(Event::new().comment("hi").to_bytes(), ":hi\n\n");
+        assert_eq!(
+            Event::new().comment("x\rdata: injected").to_bytes(),
+            ":x\n:data: injected\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_alive_and_disconnect() {
+        let (sender, sse) = Sse::channel(4);
+        let request = Request::builder()
+            .header("last-event-id", "7")
+            .body(())
+            .unwrap();
+        assert_eq!(last_event_id(&request), Some("7"));
+
+        let response = sse
+            .keep_alive(Some(Duration::from_millis(50)))
+            .into_response(&request);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+        assert!(response.extensions().get::<NoCompression>().is_some());
+
+        let mut body = response.into_body();
+        sender.send(Event::new().data("x")).await.unwrap();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "data: x\n\n");
+
+        // Stream is idle until the keep-alive deadline
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), ":\n\n");
+
+        drop(body);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(Event::new()).await, Err(Disconnected));
+    }
+}
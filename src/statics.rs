@@ -0,0 +1,838 @@
+//!
+//! A module that serves static files from a directory
+//!
+//! [StaticFiles] is a catch-all route; Mount it anywhere in the route tree
+//! and it serves every path below it from its root directory.
+//!
+//! - Paths escaping the root directory, even via symbolic links, are answered with `404 Not Found`
+//! - `Content-Type` is guessed from file extension
+//! - `ETag` and `Last-Modified` are derived from file metadata,
+//!   and conditional requests are answered with `304 Not Modified` or `412 Precondition Failed`
+//! - Byte ranges are served with `206 Partial Content`
+//! - Precompressed siblings (`.br`, `.gz`) are served if client accepts them
+//!
+//! Files can be also embedded into the binary with [crate::embed_dir]
+//! and served with [StaticFiles::embedded].
+//!
+
+use crate::body::Body;
+use crate::compression::{Compression, Encoding};
+use crate::range::RangeSource;
+use crate::response::{append_vary, ResponseBuilder};
+use crate::route::{Error, Route, Subpath};
+use crate::{conditional, range};
+use async_trait::async_trait;
+use headers::{ETag, HeaderMapExt, LastModified};
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, ALLOW, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_TYPE, LOCATION,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use include_dir::Dir;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::fs::File;
+
+#[doc(hidden)]
+pub use include_dir as __include_dir;
+
+///
+/// Embed directory into the binary at build time, creating [EmbeddedDir]
+///
+/// Path is either absolute or starts with `$CARGO_MANIFEST_DIR`.
+/// Other environment variables aren't supported.
+///
+/// # Panics
+///
+/// Panics if path is neither absolute nor starts with `$CARGO_MANIFEST_DIR`.
+///
+/// # Examples
+///
+/// ```ignore
+/// use humus_terra::embed_dir;
+/// use humus_terra::statics::StaticFiles;
+///
+/// let assets = StaticFiles::embedded("assets", embed_dir!("$CARGO_MANIFEST_DIR/public"))
+///     .read_from_disk(cfg!(debug_assertions));
+/// ```
+///
+#[macro_export]
+macro_rules! embed_dir {
+    ($path:tt) => {{
+        use $crate::statics::__include_dir as include_dir;
+        static DIR: include_dir::Dir<'static> = include_dir::include_dir!($path);
+        $crate::statics::EmbeddedDir::new(&DIR, $path, env!("CARGO_MANIFEST_DIR"))
+    }};
+}
+
+///
+/// A directory embedded into the binary; See [crate::embed_dir]
+///
+#[derive(Clone, Debug)]
+pub struct EmbeddedDir {
+    dir: &'static Dir<'static>,
+    path: PathBuf,
+}
+
+impl EmbeddedDir {
+    #[doc(hidden)]
+    pub fn new(dir: &'static Dir<'static>, path: &str, manifest_dir: &str) -> Self {
+        let path = match path.strip_prefix("$CARGO_MANIFEST_DIR") {
+            Some(relative) => PathBuf::from(format!("{}{}", manifest_dir, relative)),
+            None => PathBuf::from(path),
+        };
+        assert!(
+            path.is_absolute(),
+            "Embedded directory must be absolute or start with $CARGO_MANIFEST_DIR: {}",
+            path.display()
+        );
+
+        Self { dir, path }
+    }
+
+    ///
+    /// Get path of directory on disk that was embedded
+    ///
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    Disk(PathBuf),
+    Embedded(EmbeddedDir),
+}
+
+///
+/// A route that serves static files from a directory
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::statics::StaticFiles;
+///
+/// let assets = StaticFiles::new("assets", "./public")
+///     .index_files(&["index.html"])
+///     .precompressed(true)
+///     .cache_control("public, max-age=3600")
+///     .cache_control_for("html", "no-cache");
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    name: String,
+    source: Source,
+    read_from_disk: bool,
+    index_files: Vec<String>,
+    directory_listing: bool,
+    precompressed: bool,
+    hidden_files: bool,
+    cache_control: Option<String>,
+    cache_control_rules: Vec<(String, String)>,
+}
+
+enum Content {
+    Disk(PathBuf),
+    Embedded(&'static [u8]),
+}
+
+impl Content {
+    async fn open(&self, length: u64) -> io::Result<RangeSource> {
+        Ok(match self {
+            Content::Disk(path) => RangeSource::reader(File::open(path).await?, length),
+            Content::Embedded(bytes) => RangeSource::from(*bytes),
+        })
+    }
+}
+
+///
+/// A file resolved for request
+///
+struct ResolvedFile {
+    /// Path relative to root
+    path: PathBuf,
+    length: u64,
+    modified: Option<SystemTime>,
+    content: Content,
+}
+
+enum Resolved {
+    Dir,
+    File(ResolvedFile),
+}
+
+impl StaticFiles {
+    ///
+    /// Create new route named `name` that serves files under `root`.
+    ///
+    /// Directories are served with `index.html` if it exists.
+    ///
+    pub fn new(name: &str, root: impl Into<PathBuf>) -> Self {
+        Self::with_source(name, Source::Disk(root.into()))
+    }
+
+    ///
+    /// Create new route named `name` that serves files embedded into the binary.
+    /// See [crate::embed_dir]
+    ///
+    pub fn embedded(name: &str, dir: EmbeddedDir) -> Self {
+        Self::with_source(name, Source::Embedded(dir))
+    }
+
+    fn with_source(name: &str, source: Source) -> Self {
+        Self {
+            name: name.to_string(),
+            source,
+            read_from_disk: false,
+            index_files: vec!["index.html".to_string()],
+            directory_listing: false,
+            precompressed: false,
+            hidden_files: false,
+            cache_control: None,
+            cache_control_rules: vec![],
+        }
+    }
+
+    ///
+    /// Set files to look up, in order, when directory is requested
+    ///
+    pub fn index_files(mut self, index_files: &[&str]) -> Self {
+        self.index_files = index_files.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    ///
+    /// Set whether to list contents of directories without index file
+    ///
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.directory_listing = enabled;
+        self
+    }
+
+    ///
+    /// Set whether to serve precompressed siblings of files;
+    /// `style.css.br` or `style.css.gz` for `style.css`
+    ///
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    ///
+    /// Set whether to read embedded files from the directory they were embedded from.
+    ///
+    /// This lets development builds pick up changes without rebuilding;
+    /// e.g. `.read_from_disk(cfg!(debug_assertions))`.
+    /// It has no effect on routes serving files from disk already.
+    ///
+    pub fn read_from_disk(mut self, enabled: bool) -> Self {
+        self.read_from_disk = enabled;
+        self
+    }
+
+    ///
+    /// Set whether to serve files and directories whose name starts with `.`
+    ///
+    pub fn hidden_files(mut self, enabled: bool) -> Self {
+        self.hidden_files = enabled;
+        self
+    }
+
+    ///
+    /// Set default value of `Cache-Control` header for served files
+    ///
+    pub fn cache_control(mut self, value: &str) -> Self {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    ///
+    /// Set value of `Cache-Control` header for files with given extension,
+    /// overriding default one
+    ///
+    pub fn cache_control_for(mut self, extension: &str, value: &str) -> Self {
+        self.cache_control_rules
+            .push((extension.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    fn cache_control_of(&self, path: &Path) -> Option<&str> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        extension
+            .and_then(|extension| {
+                self.cache_control_rules
+                    .iter()
+                    .find(|(rule, _)| *rule == extension)
+                    .map(|(_, value)| value.as_str())
+            })
+            .or(self.cache_control.as_deref())
+    }
+
+    ///
+    /// Convert subpath to relative path, rejecting segments that could escape root
+    ///
+    fn relative_path(&self, subpath: &Subpath) -> Option<PathBuf> {
+        let mut path = PathBuf::new();
+        for segment in subpath.segments() {
+            let segment = percent_decode(segment)?;
+            if segment.is_empty()
+                || segment == "."
+                || segment == ".."
+                || segment.contains(['/', '\\', '\0'])
+                || (!self.hidden_files && segment.starts_with('.'))
+            {
+                return None;
+            }
+
+            path.push(segment);
+        }
+
+        Some(path)
+    }
+
+    fn disk_root(&self) -> Option<&Path> {
+        match &self.source {
+            Source::Disk(root) => Some(root),
+            Source::Embedded(embedded) if self.read_from_disk => Some(&embedded.path),
+            Source::Embedded(_) => None,
+        }
+    }
+
+    ///
+    /// Resolve given relative path under root, following symbolic links.
+    /// Returns `None` if it doesn't exist or it's outside of root
+    ///
+    async fn resolve(&self, relative: &Path) -> io::Result<Option<Resolved>> {
+        let root = match (self.disk_root(), &self.source) {
+            (Some(root), _) => root,
+            (None, Source::Embedded(embedded)) => {
+                return Ok(resolve_embedded(embedded.dir, relative))
+            }
+            (None, Source::Disk(_)) => unreachable!(),
+        };
+
+        let root = fs::canonicalize(root).await?;
+        let path = match fs::canonicalize(root.join(relative)).await {
+            Ok(path) => path,
+            Err(e) if is_not_found(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        if !path.starts_with(&root) {
+            return Ok(None);
+        }
+
+        let metadata = fs::metadata(&path).await?;
+        if metadata.is_dir() {
+            return Ok(Some(Resolved::Dir));
+        }
+
+        Ok(Some(Resolved::File(ResolvedFile {
+            path: relative.to_path_buf(),
+            length: metadata.len(),
+            modified: metadata.modified().ok(),
+            content: Content::Disk(path),
+        })))
+    }
+
+    ///
+    /// List names of entries in directory at given relative path, with whether they're directories
+    ///
+    async fn list(&self, relative: &Path) -> io::Result<Vec<(String, bool)>> {
+        let mut entries = vec![];
+
+        let root = match (self.disk_root(), &self.source) {
+            (Some(root), _) => root,
+            (None, Source::Embedded(embedded)) => {
+                let dir = match relative.as_os_str().is_empty() {
+                    true => Some(embedded.dir),
+                    false => embedded.dir.get_dir(relative),
+                };
+                for entry in dir.map(|dir| dir.entries()).unwrap_or_default() {
+                    if let Some(name) = entry.path().file_name().and_then(|name| name.to_str()) {
+                        entries.push((name.to_string(), entry.as_dir().is_some()));
+                    }
+                }
+                return Ok(entries);
+            }
+            (None, Source::Disk(_)) => unreachable!(),
+        };
+
+        let mut reader = fs::read_dir(root.join(relative)).await?;
+        while let Some(entry) = reader.next_entry().await? {
+            if let Ok(name) = entry.file_name().into_string() {
+                entries.push((name, entry.file_type().await?.is_dir()));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    async fn serve<B>(&self, request: &Request<B>) -> Result<Response<Body>, Box<Error>> {
+        let not_found = || {
+            ResponseBuilder::from_request(request)
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+        };
+
+        let relative = Subpath::from_request(request)
+            .map(|subpath| self.relative_path(subpath))
+            .unwrap_or_else(|| Some(PathBuf::new()));
+        let relative = match relative {
+            None => return Ok(not_found()?),
+            Some(relative) => relative,
+        };
+
+        match self.resolve(&relative).await? {
+            None => return Ok(not_found()?),
+            Some(Resolved::File(file)) => return self.serve_file(request, file).await,
+            Some(Resolved::Dir) => {}
+        }
+
+        // Relative links in directory must resolve under it
+        let path = request.uri().path();
+        if !path.ends_with('/') {
+            let location = match request.uri().query() {
+                None => format!("{}/", path),
+                Some(query) => format!("{}/?{}", path, query),
+            };
+            return Ok(ResponseBuilder::from_request(request)
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(LOCATION, location)
+                .body(Body::empty())?);
+        }
+
+        for index_file in &self.index_files {
+            if let Some(Resolved::File(index)) = self.resolve(&relative.join(index_file)).await? {
+                return self.serve_file(request, index).await;
+            }
+        }
+
+        if self.directory_listing {
+            return self.list_directory(request, &relative).await;
+        }
+
+        Ok(not_found()?)
+    }
+
+    async fn serve_file<B>(
+        &self,
+        request: &Request<B>,
+        resolved: ResolvedFile,
+    ) -> Result<Response<Body>, Box<Error>> {
+        let content_type = mime_type(&resolved.path);
+        let cache_control = self.cache_control_of(&resolved.path);
+
+        // Look up precompressed sibling, preferring brotli
+        let mut encoding = None;
+        let mut file = resolved;
+        if self.precompressed {
+            let accept_encoding = request.headers().get(ACCEPT_ENCODING);
+            for (candidate, extension) in [(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")] {
+                let accepted = Compression::new()
+                    .encodings(&[candidate])
+                    .negotiate(accept_encoding)
+                    .is_some();
+                if !accepted {
+                    continue;
+                }
+
+                let mut sibling = file.path.clone().into_os_string();
+                sibling.push(".");
+                sibling.push(extension);
+                if let Some(Resolved::File(sibling)) = self.resolve(Path::new(&sibling)).await? {
+                    file = sibling;
+                    encoding = Some(candidate);
+                    break;
+                }
+            }
+        }
+
+        let length = file.length;
+        let modified = file.modified;
+        let etag = etag_of(length, modified, encoding);
+
+        let mut builder = ResponseBuilder::from_request(request);
+        {
+            let headers = builder.headers_mut().unwrap();
+            headers.typed_insert(etag.clone());
+            if let Some(modified) = modified {
+                headers.typed_insert(LastModified::from(modified));
+            }
+            if let Some(cache_control) = cache_control {
+                headers.insert(CACHE_CONTROL, HeaderValue::from_str(cache_control)?);
+            }
+            if self.precompressed {
+                append_vary(headers, ACCEPT_ENCODING);
+            }
+        }
+
+        if let Some(status) =
+            conditional::evaluate(request.method(), request.headers(), Some(&etag), modified)
+        {
+            return Ok(builder.status(status).body(Body::empty())?);
+        }
+
+        builder = builder.header(CONTENT_TYPE, &content_type);
+        if let Some(encoding) = encoding {
+            builder = builder.header(CONTENT_ENCODING, encoding.as_str());
+        }
+
+        let source = file.content.open(length).await?;
+        Ok(range::respond(request, builder, source)?)
+    }
+
+    async fn list_directory<B>(
+        &self,
+        request: &Request<B>,
+        relative: &Path,
+    ) -> Result<Response<Body>, Box<Error>> {
+        let mut entries = self.list(relative).await?;
+        entries.retain(|(name, _)| self.hidden_files || !name.starts_with('.'));
+        entries.sort();
+
+        let title = escape_html(&percent_decode(request.uri().path()).unwrap_or_default());
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+            title
+        );
+        for (name, is_dir) in entries {
+            let suffix = if is_dir { "/" } else { "" };
+            html.push_str(&format!(
+                "<li><a href=\"{}{}\">{}{}</a></li>\n",
+                percent_encode(&name),
+                suffix,
+                escape_html(&name),
+                suffix
+            ));
+        }
+        html.push_str("</ul>\n</body>\n</html>\n");
+
+        let body = if request.method() == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::from(html.clone())
+        };
+
+        Ok(ResponseBuilder::from_request(request)
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(CONTENT_LENGTH, html.len())
+            .header(CACHE_CONTROL, "no-cache")
+            .body(body)?)
+    }
+}
+
+#[async_trait]
+impl Route for StaticFiles {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_catch_all(&self) -> bool {
+        true
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Result<Response<Body>, Box<Error>> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return Ok(ResponseBuilder::from_request(&request)
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(ALLOW, "GET, HEAD")
+                .body(Body::empty())?);
+        }
+
+        self.serve(&request).await
+    }
+}
+
+fn resolve_embedded(dir: &'static Dir<'static>, relative: &Path) -> Option<Resolved> {
+    if relative.as_os_str().is_empty() || dir.get_dir(relative).is_some() {
+        return Some(Resolved::Dir);
+    }
+
+    let file = dir.get_file(relative)?;
+    let contents: &'static [u8] = file.contents();
+    Some(Resolved::File(ResolvedFile {
+        path: relative.to_path_buf(),
+        length: contents.len() as u64,
+        modified: file.metadata().map(|metadata| metadata.modified()),
+        content: Content::Embedded(contents),
+    }))
+}
+
+fn is_not_found(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+fn mime_type(path: &Path) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    if mime.type_() == mime_guess::mime::TEXT && mime.get_param("charset").is_none() {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+fn etag_of(length: u64, modified: Option<SystemTime>, encoding: Option<Encoding>) -> ETag {
+    let modified = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    let etag = match encoding {
+        None => format!("\"{:x}-{:x}\"", length, modified),
+        Some(encoding) => format!("\"{:x}-{:x}-{}\"", length, modified, encoding.as_str()),
+    };
+    etag.parse().unwrap()
+}
+
+///
+/// Decode percent-encoded string; Returns `None` if it's malformed or it's not UTF-8
+///
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{CONTENT_RANGE, ETAG, IF_NONE_MATCH, RANGE, VARY};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fn fixture() -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "humus-statics-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        std::fs::write(root.join("hello.txt"), "hello, world").unwrap();
+        std::fs::write(root.join("hello.txt.gz"), "compressed").unwrap();
+        std::fs::write(root.join(".secret"), "secret").unwrap();
+        std::fs::write(root.join("docs/a b.md"), "# a b").unwrap();
+        root
+    }
+
+    fn get(path: &str, subpath: &str) -> Request<Body> {
+        let mut request = Request::builder().uri(path).body(Body::empty()).unwrap();
+        request.extensions_mut().insert(Subpath::new(subpath));
+        request
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let bytes = response.into_body().to_bytes().await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_file() {
+        let root = fixture();
+        let route = StaticFiles::new("static", &root).cache_control("max-age=60");
+
+        let response = route
+            .handle(get("/static/hello.txt", "hello.txt"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.headers()[CACHE_CONTROL], "max-age=60");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "12");
+        let etag = response.headers()[ETAG].clone();
+        assert_eq!(body(response).await, "hello, world");
+
+        let mut request = get("/static/hello.txt", "hello.txt");
+        request.headers_mut().insert(IF_NONE_MATCH, etag);
+        let response = route.handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = route.handle(get("/static/", "")).await.unwrap();
+        assert_eq!(body(response).await, "<h1>home</h1>");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn traversal() {
+        let root = fixture();
+        let route = StaticFiles::new("static", root.join("docs"));
+
+        for subpath in [
+            "../hello.txt",
+            "%2e%2e/hello.txt",
+            "..%2fhello.txt",
+            "a%00b",
+        ] {
+            let response = route.handle(get("/static/x", subpath)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", subpath);
+        }
+
+        let route = StaticFiles::new("static", &root);
+        let response = route
+            .handle(get("/static/.secret", ".secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = route
+            .handle(get("/static/docs/a%20b.md", "docs/a%20b.md"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn directory() {
+        let root = fixture();
+        let route = StaticFiles::new("static", &root).directory_listing(true);
+
+        let response = route.handle(get("/static/docs?x=1", "docs")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/static/docs/?x=1");
+
+        let response = route.handle(get("/static/docs/", "docs")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(response)
+            .await
+            .contains("<a href=\"a%20b.md\">a b.md</a>"));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ranges_and_precompressed() {
+        let root = fixture();
+        let route = StaticFiles::new("static", &root).precompressed(true);
+
+        let mut request = get("/static/hello.txt", "hello.txt");
+        request
+            .headers_mut()
+            .insert(RANGE, HeaderValue::from_static("bytes=7-"));
+        let response = route.handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-11/12");
+        assert_eq!(body(response).await, "world");
+
+        let mut request = get("/static/hello.txt", "hello.txt");
+        request
+            .headers_mut()
+            .insert(RANGE, HeaderValue::from_static("bytes=12-"));
+        let response = route.handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */12");
+
+        let mut request = get("/static/hello.txt", "hello.txt");
+        request
+            .headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, br"));
+        let response = route.handle(request).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(response.headers()[VARY], "accept-encoding");
+        assert_eq!(body(response).await, "compressed");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn embedded() {
+        let expected = include_str!("../tests/fixtures/static/docs/hello.txt");
+
+        for read_from_disk in [false, true] {
+            let route = StaticFiles::embedded(
+                "static",
+                crate::embed_dir!("$CARGO_MANIFEST_DIR/tests/fixtures/static"),
+            )
+            .directory_listing(true)
+            .read_from_disk(read_from_disk);
+
+            let response = route
+                .handle(get("/static/docs/hello.txt", "docs/hello.txt"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().contains_key(ETAG));
+            assert_eq!(body(response).await, expected);
+
+            let response = route.handle(get("/static/docs/", "docs")).await.unwrap();
+            assert!(body(response)
+                .await
+                .contains("<a href=\"hello.txt\">hello.txt</a>"));
+
+            let response = route
+                .handle(get("/static/missing", "missing"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "must be absolute or start with $CARGO_MANIFEST_DIR")]
+    fn embedded_unsupported_variable() {
+        static DIR: Dir<'static> = Dir::new("", &[]);
+        EmbeddedDir::new(&DIR, "$OUT_DIR/assets", env!("CARGO_MANIFEST_DIR"));
+    }
+}
@@ -0,0 +1,826 @@
+//!
+//! A module that provides server-side state of refresh tokens
+//!
+//! Refresh tokens of a session form a family; Each refresh replaces the current
+//! token of the family with a new one. If a token that was already replaced is
+//! presented again, someone holds a copy of it, so the whole family is revoked.
+//!
+//! Concurrent requests of a client may present the same token before it receives
+//! the new one, so the token just replaced is accepted again for a grace period,
+//! 10 seconds by default, and the same new token is handed out.
+//!
+//! Assign a store with [crate::tokens::TokenConfig::store] to track families,
+//! and to log out of a session or of every session of a user.
+//!
+//! Access tokens are valid until they expire regardless of families. To reject them
+//! earlier, like after password change, assign a [RevocationList] with
+//! [crate::tokens::TokenConfig::revocations].
+//!
+//! # Examples
+//!
+//! ```
+//! use humus_terra::token_store::RevocationList;
+//! use humus_terra::tokens::TokenConfig;
+//!
+//! let revocations = RevocationList::new();
+//! TokenConfig::set(TokenConfig::new("secret", true).revocations(revocations.clone()));
+//!
+//! async fn lock_account(revocations: &RevocationList, who: &str) {
+//!     revocations.revoke_subject(who, chrono::Utc::now()).await.unwrap();
+//! }
+//! ```
+//!
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+///
+/// An error-type alias for token stores
+///
+pub type Error = dyn std::error::Error + Send + Sync;
+
+///
+/// A refresh of token family
+///
+#[derive(Clone, Debug)]
+pub struct Refresh<'a> {
+    ///
+    /// Who has the tokens
+    ///
+    pub who: &'a str,
+    ///
+    /// ID of token family
+    ///
+    pub family: i64,
+    ///
+    /// ID of presented refresh token
+    ///
+    pub token: i64,
+    ///
+    /// When presented refresh token was issued, in seconds since UNIX epoch
+    ///
+    pub issued: i64,
+    ///
+    /// ID of refresh token replacing presented one
+    ///
+    pub next: i64,
+    ///
+    /// When the family expires unless refreshed, in seconds since UNIX epoch
+    ///
+    pub expires: i64,
+}
+
+///
+/// An outcome of refresh
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    ///
+    /// Presented token is replaced with next one
+    ///
+    Rotated,
+    ///
+    /// Presented token was replaced with given one within grace period,
+    /// likely by concurrent request of the same client; Hand out the same one
+    ///
+    Concurrent {
+        ///
+        /// ID of refresh token replacing presented one
+        ///
+        next: i64,
+    },
+    ///
+    /// Presented token was replaced already, so the family is revoked now
+    ///
+    Reused,
+    ///
+    /// The family was revoked
+    ///
+    Revoked,
+}
+
+///
+/// A store of refresh token families
+///
+/// Families are registered by their first refresh, so new sessions don't touch the store.
+///
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    ///
+    /// Replace current token of family with next one.
+    ///
+    /// Revokes the family if presented token is not the current one,
+    /// unless it was replaced within grace period; See [Rotation::Concurrent].
+    /// Fails with [Rotation::Revoked] if the family is revoked, or the token was
+    /// issued before [TokenStore::revoke_all] for its owner.
+    ///
+    async fn rotate(&self, refresh: &Refresh<'_>) -> Result<Rotation, Box<Error>>;
+
+    ///
+    /// Revoke token family; Log out of a session
+    ///
+    async fn revoke(&self, family: i64, expires: i64) -> Result<(), Box<Error>>;
+
+    ///
+    /// Revoke every family of given owner issued before given time; Log out everywhere
+    ///
+    async fn revoke_all(&self, who: &str, before: i64) -> Result<(), Box<Error>>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Family {
+    // None if revoked
+    current: Option<i64>,
+    expires: i64,
+    // Token replaced by current one, and when
+    #[serde(default)]
+    previous: Option<(i64, i64)>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Families {
+    families: HashMap<i64, Family>,
+    // Tokens of owner issued before are revoked
+    cutoffs: HashMap<String, (i64, i64)>,
+}
+
+impl Families {
+    fn rotate(&mut self, refresh: &Refresh<'_>, now: i64, grace_period: i64) -> Rotation {
+        let cutoff = self.cutoffs.get(refresh.who);
+        if cutoff.is_some_and(|(before, _)| refresh.issued < *before) {
+            return Rotation::Revoked;
+        }
+
+        let family = self.families.entry(refresh.family).or_insert(Family {
+            current: Some(refresh.token),
+            expires: refresh.expires,
+            previous: None,
+        });
+        match family.current {
+            None => Rotation::Revoked,
+            Some(current) if current == refresh.token => {
+                family.current = Some(refresh.next);
+                family.expires = refresh.expires;
+                family.previous = Some((refresh.token, now));
+                Rotation::Rotated
+            }
+            Some(current)
+                if family.previous.is_some_and(|(previous, rotated)| {
+                    previous == refresh.token && now - rotated < grace_period
+                }) =>
+            {
+                Rotation::Concurrent { next: current }
+            }
+            Some(_) => {
+                family.current = None;
+                Rotation::Reused
+            }
+        }
+    }
+
+    fn revoke(&mut self, family: i64, expires: i64) {
+        let family = self.families.entry(family).or_insert(Family {
+            current: None,
+            expires,
+            previous: None,
+        });
+        family.current = None;
+        family.expires = family.expires.max(expires);
+    }
+
+    fn revoke_all(&mut self, who: &str, before: i64, expires: i64) {
+        let cutoff = self
+            .cutoffs
+            .entry(who.to_string())
+            .or_insert((before, expires));
+        *cutoff = (cutoff.0.max(before), cutoff.1.max(expires));
+    }
+
+    // Forget families and cutoffs whose tokens expired anyway
+    fn prune(&mut self, now: i64) {
+        self.families.retain(|_, family| family.expires > now);
+        self.cutoffs.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+// How long replaced token is accepted again by default
+static GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+///
+/// A store keeping families in memory, which are lost on restart
+///
+pub struct MemoryTokenStore {
+    families: Mutex<Families>,
+    grace_period: Duration,
+}
+
+impl MemoryTokenStore {
+    ///
+    /// Create new empty store
+    ///
+    pub fn new() -> Self {
+        Self {
+            families: Mutex::default(),
+            grace_period: GRACE_PERIOD,
+        }
+    }
+
+    ///
+    /// Set how long replaced token is accepted again; 10 seconds by default
+    ///
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+}
+
+impl Default for MemoryTokenStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn rotate(&self, refresh: &Refresh<'_>) -> Result<Rotation, Box<Error>> {
+        let now = chrono::Utc::now().timestamp();
+        let mut families = self.families.lock().await;
+        families.prune(now);
+        Ok(families.rotate(refresh, now, self.grace_period.as_secs() as i64))
+    }
+
+    async fn revoke(&self, family: i64, expires: i64) -> Result<(), Box<Error>> {
+        self.families.lock().await.revoke(family, expires);
+        Ok(())
+    }
+
+    async fn revoke_all(&self, who: &str, before: i64) -> Result<(), Box<Error>> {
+        // Tokens issued before never outlive the longest lifetime
+        let expires = before.saturating_add(REVOCATION_RETENTION);
+        self.families.lock().await.revoke_all(who, before, expires);
+        Ok(())
+    }
+}
+
+// How long cutoffs of log out everywhere are kept; Longer than any sane refresh lifetime
+static REVOCATION_RETENTION: i64 = 400 * 24 * 60 * 60;
+
+///
+/// A store keeping families in a JSON file, which survives restart
+///
+/// The file is rewritten on every change, so it suits a single server with
+/// moderate number of sessions.
+///
+pub struct FileTokenStore {
+    path: PathBuf,
+    families: Mutex<Families>,
+    grace_period: Duration,
+}
+
+impl FileTokenStore {
+    ///
+    /// Open store of given file, creating it if it doesn't exist
+    ///
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, Box<Error>> {
+        let path = path.as_ref().to_path_buf();
+        let families = read_json(&path).await?;
+
+        Ok(Self {
+            path,
+            families: Mutex::new(families),
+            grace_period: GRACE_PERIOD,
+        })
+    }
+
+    ///
+    /// Set how long replaced token is accepted again; 10 seconds by default
+    ///
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    async fn persist(&self, families: &Families) -> Result<(), Box<Error>> {
+        write_json(&self.path, families).await
+    }
+}
+
+async fn read_json<T: Default + for<'de> Deserialize<'de>>(path: &Path) -> Result<T, Box<Error>> {
+    match tokio::fs::read(path).await {
+        Ok(json) => Ok(serde_json::from_slice(&json)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+// Write to temporary file and rename, so the file is never half-written
+async fn write_json(path: &Path, value: &impl Serialize) -> Result<(), Box<Error>> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+
+    tokio::fs::write(&temporary, serde_json::to_vec(value)?).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+#[async_trait]
+impl TokenStore for FileTokenStore {
+    async fn rotate(&self, refresh: &Refresh<'_>) -> Result<Rotation, Box<Error>> {
+        let now = chrono::Utc::now().timestamp();
+        let mut families = self.families.lock().await;
+        families.prune(now);
+        let rotation = families.rotate(refresh, now, self.grace_period.as_secs() as i64);
+        self.persist(&families).await?;
+        Ok(rotation)
+    }
+
+    async fn revoke(&self, family: i64, expires: i64) -> Result<(), Box<Error>> {
+        let mut families = self.families.lock().await;
+        families.revoke(family, expires);
+        self.persist(&families).await
+    }
+
+    async fn revoke_all(&self, who: &str, before: i64) -> Result<(), Box<Error>> {
+        let mut families = self.families.lock().await;
+        let expires = before.saturating_add(REVOCATION_RETENTION);
+        families.revoke_all(who, before, expires);
+        self.persist(&families).await
+    }
+}
+
+///
+/// A revocation of tokens, which is kept until tokens it revokes expire
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Revocation {
+    ///
+    /// Token of given nonce, which is `jti` claim of JWT
+    ///
+    Token {
+        ///
+        /// Nonce of token
+        ///
+        nonce: i64,
+        ///
+        /// When the revocation expires, in seconds since UNIX epoch
+        ///
+        expires: i64,
+    },
+    ///
+    /// Tokens of given owner issued before given time
+    ///
+    Subject {
+        ///
+        /// Who has the tokens
+        ///
+        who: String,
+        ///
+        /// When tokens issued before are revoked, in seconds since UNIX epoch
+        ///
+        before: i64,
+        ///
+        /// When the revocation expires, in seconds since UNIX epoch
+        ///
+        expires: i64,
+    },
+    ///
+    /// Every token issued before given time
+    ///
+    All {
+        ///
+        /// When tokens issued before are revoked, in seconds since UNIX epoch
+        ///
+        before: i64,
+        ///
+        /// When the revocation expires, in seconds since UNIX epoch
+        ///
+        expires: i64,
+    },
+}
+
+impl Revocation {
+    fn expires(&self) -> i64 {
+        match self {
+            Revocation::Token { expires, .. }
+            | Revocation::Subject { expires, .. }
+            | Revocation::All { expires, .. } => *expires,
+        }
+    }
+}
+
+///
+/// A persistent backend of [RevocationList], which may be shared by servers
+///
+#[async_trait]
+pub trait RevocationBackend: Send + Sync {
+    ///
+    /// Persist revocation
+    ///
+    async fn revoke(&self, revocation: &Revocation) -> Result<(), Box<Error>>;
+
+    ///
+    /// Load revocations which aren't expired at given time, in seconds since UNIX epoch
+    ///
+    async fn load(&self, now: i64) -> Result<Vec<Revocation>, Box<Error>>;
+}
+
+///
+/// A backend keeping revocations in a JSON file, which survives restart
+///
+/// The file is read on every reload, so servers sharing a file system share revocations.
+///
+pub struct FileRevocations {
+    path: PathBuf,
+    // Serializes read-modify-write of the file
+    lock: Mutex<()>,
+}
+
+impl FileRevocations {
+    ///
+    /// Open backend of given file, creating it if it doesn't exist
+    ///
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, Box<Error>> {
+        let path = path.as_ref().to_path_buf();
+        read_json::<Vec<Revocation>>(&path).await?;
+
+        Ok(Self {
+            path,
+            lock: Mutex::new(()),
+        })
+    }
+}
+
+#[async_trait]
+impl RevocationBackend for FileRevocations {
+    async fn revoke(&self, revocation: &Revocation) -> Result<(), Box<Error>> {
+        let _lock = self.lock.lock().await;
+        let mut revocations = self.load(Utc::now().timestamp()).await?;
+        revocations.push(revocation.clone());
+        write_json(&self.path, &revocations).await
+    }
+
+    async fn load(&self, now: i64) -> Result<Vec<Revocation>, Box<Error>> {
+        let mut revocations: Vec<Revocation> = read_json(&self.path).await?;
+        revocations.retain(|revocation| revocation.expires() > now);
+        Ok(revocations)
+    }
+}
+
+#[derive(Default)]
+struct Revocations {
+    // Nonce to expiry
+    tokens: HashMap<i64, i64>,
+    // Owner to cutoff and expiry
+    subjects: HashMap<String, (i64, i64)>,
+    all: Option<(i64, i64)>,
+}
+
+impl Revocations {
+    fn insert(&mut self, revocation: &Revocation) {
+        let merge = |cutoff: &mut (i64, i64), before: i64, expires: i64| {
+            *cutoff = (cutoff.0.max(before), cutoff.1.max(expires));
+        };
+        match revocation {
+            Revocation::Token { nonce, expires } => {
+                let entry = self.tokens.entry(*nonce).or_insert(*expires);
+                *entry = (*entry).max(*expires);
+            }
+            Revocation::Subject {
+                who,
+                before,
+                expires,
+            } => merge(
+                self.subjects
+                    .entry(who.clone())
+                    .or_insert((*before, *expires)),
+                *before,
+                *expires,
+            ),
+            Revocation::All { before, expires } => merge(
+                self.all.get_or_insert((*before, *expires)),
+                *before,
+                *expires,
+            ),
+        }
+    }
+
+    fn revoked(&self, nonce: i64, who: &str, issued: i64) -> bool {
+        self.tokens.contains_key(&nonce)
+            || self
+                .subjects
+                .get(who)
+                .is_some_and(|(before, _)| issued < *before)
+            || self.all.is_some_and(|(before, _)| issued < before)
+    }
+
+    fn prune(&mut self, now: i64) {
+        self.tokens.retain(|_, expires| *expires > now);
+        self.subjects.retain(|_, (_, expires)| *expires > now);
+        self.all = self.all.filter(|(_, expires)| *expires > now);
+    }
+}
+
+///
+/// A list of revoked tokens, which is consulted by [crate::tokens::Session::from_request]
+///
+/// Revocations are cached in memory, so checking tokens doesn't touch the backend
+/// until the cache is older than reload interval. Clones share the cache.
+///
+#[derive(Clone)]
+pub struct RevocationList {
+    backend: Option<Arc<dyn RevocationBackend>>,
+    reload_interval: Duration,
+    cache: Arc<RwLock<(Revocations, Option<Instant>)>>,
+}
+
+impl Default for RevocationList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RevocationList {
+    ///
+    /// Create new list kept only in memory, which is lost on restart
+    ///
+    pub fn new() -> Self {
+        Self {
+            backend: None,
+            reload_interval: Duration::from_secs(60),
+            cache: Arc::default(),
+        }
+    }
+
+    ///
+    /// Persist revocations in given backend, and load ones of other servers from it
+    ///
+    pub fn backend(mut self, backend: impl RevocationBackend + 'static) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    ///
+    /// Set how often revocations are reloaded from backend, and expired ones are
+    /// dropped from memory; Defaults to 60 seconds
+    ///
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    ///
+    /// Revoke token of given nonce, which is `jti` claim of JWT
+    ///
+    pub async fn revoke_token(&self, nonce: i64) -> Result<(), Box<Error>> {
+        let expires = Utc::now().timestamp().saturating_add(REVOCATION_RETENTION);
+        self.revoke(Revocation::Token { nonce, expires }).await
+    }
+
+    ///
+    /// Revoke tokens of given owner issued before given time.
+    /// Tokens issued at the very second stay valid, so a session created right after is kept.
+    ///
+    pub async fn revoke_subject(&self, who: &str, before: DateTime<Utc>) -> Result<(), Box<Error>> {
+        let before = before.timestamp();
+        self.revoke(Revocation::Subject {
+            who: who.to_string(),
+            before,
+            expires: before.saturating_add(REVOCATION_RETENTION),
+        })
+        .await
+    }
+
+    ///
+    /// Revoke every token issued before given time
+    ///
+    pub async fn revoke_all(&self, before: DateTime<Utc>) -> Result<(), Box<Error>> {
+        let before = before.timestamp();
+        self.revoke(Revocation::All {
+            before,
+            expires: before.saturating_add(REVOCATION_RETENTION),
+        })
+        .await
+    }
+
+    async fn revoke(&self, revocation: Revocation) -> Result<(), Box<Error>> {
+        if let Some(backend) = &self.backend {
+            backend.revoke(&revocation).await?;
+        }
+
+        let mut cache = self.write();
+        cache.0.prune(Utc::now().timestamp());
+        cache.0.insert(&revocation);
+        Ok(())
+    }
+
+    ///
+    /// Check whether token of given nonce, owner and issued time is revoked
+    ///
+    pub async fn is_revoked(
+        &self,
+        nonce: i64,
+        who: &str,
+        issued: DateTime<Utc>,
+    ) -> Result<bool, Box<Error>> {
+        let now = Utc::now().timestamp();
+        let loaded = self.read().1;
+        if loaded.is_none_or(|loaded| loaded.elapsed() >= self.reload_interval) {
+            let revocations = match &self.backend {
+                Some(backend) => backend.load(now).await?,
+                None => vec![],
+            };
+
+            // Revocations are never withdrawn, so merging keeps ones made meanwhile
+            let mut cache = self.write();
+            revocations
+                .iter()
+                .for_each(|revocation| cache.0.insert(revocation));
+            cache.0.prune(now);
+            cache.1 = Some(Instant::now());
+        }
+
+        Ok(self.read().0.revoked(nonce, who, issued.timestamp()))
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, (Revocations, Option<Instant>)> {
+        self.cache.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, (Revocations, Option<Instant>)> {
+        self.cache.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refresh(token: i64, next: i64) -> Refresh<'static> {
+        Refresh {
+            who: "a",
+            family: 1,
+            token,
+            issued: chrono::Utc::now().timestamp() - 100,
+            next,
+            expires: i64::MAX,
+        }
+    }
+
+    async fn rotations(store: &dyn TokenStore) {
+        assert_eq!(
+            store.rotate(&refresh(1, 2)).await.unwrap(),
+            Rotation::Rotated
+        );
+        // Concurrent request with the same token gets the same next one
+        assert_eq!(
+            store.rotate(&refresh(1, 9)).await.unwrap(),
+            Rotation::Concurrent { next: 2 }
+        );
+        assert_eq!(
+            store.rotate(&refresh(2, 3)).await.unwrap(),
+            Rotation::Rotated
+        );
+
+        // Replaying replaced token revokes the family
+        assert_eq!(
+            store.rotate(&refresh(1, 4)).await.unwrap(),
+            Rotation::Reused
+        );
+        assert_eq!(
+            store.rotate(&refresh(3, 5)).await.unwrap(),
+            Rotation::Revoked
+        );
+
+        let other = Refresh {
+            family: 2,
+            ..refresh(1, 2)
+        };
+        store.revoke(2, i64::MAX).await.unwrap();
+        assert_eq!(store.rotate(&other).await.unwrap(), Rotation::Revoked);
+
+        let later = Refresh {
+            family: 3,
+            issued: chrono::Utc::now().timestamp(),
+            ..refresh(1, 2)
+        };
+        store
+            .revoke_all("a", chrono::Utc::now().timestamp() - 50)
+            .await
+            .unwrap();
+        assert_eq!(store.rotate(&later).await.unwrap(), Rotation::Rotated);
+        let earlier = Refresh {
+            family: 4,
+            ..refresh(1, 2)
+        };
+        assert_eq!(store.rotate(&earlier).await.unwrap(), Rotation::Revoked);
+    }
+
+    #[tokio::test]
+    async fn memory() {
+        rotations(&MemoryTokenStore::new()).await;
+
+        let store = MemoryTokenStore::new();
+        let expired = Refresh {
+            expires: 0,
+            ..refresh(1, 2)
+        };
+        store.rotate(&expired).await.unwrap();
+        // Expired family is forgotten
+        assert_eq!(
+            store.rotate(&refresh(5, 6)).await.unwrap(),
+            Rotation::Rotated
+        );
+
+        let store = MemoryTokenStore::new().grace_period(Duration::ZERO);
+        store.rotate(&refresh(1, 2)).await.unwrap();
+        assert_eq!(
+            store.rotate(&refresh(1, 9)).await.unwrap(),
+            Rotation::Reused
+        );
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let path = std::env::temp_dir().join(format!("humus-terra-{}.json", rand::random::<u64>()));
+        let store = FileTokenStore::open(&path).await.unwrap();
+        rotations(&store).await;
+
+        // State survives reopening
+        let store = FileTokenStore::open(&path).await.unwrap();
+        assert_eq!(
+            store.rotate(&refresh(3, 6)).await.unwrap(),
+            Rotation::Revoked
+        );
+        let earlier = Refresh {
+            family: 5,
+            ..refresh(1, 2)
+        };
+        assert_eq!(store.rotate(&earlier).await.unwrap(), Rotation::Revoked);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn revocation_list() {
+        let now = Utc::now();
+        let issued = now - chrono::Duration::hours(1);
+        let path = std::env::temp_dir().join(format!("humus-terra-{}.json", rand::random::<u64>()));
+        let list = RevocationList::new().backend(FileRevocations::open(&path).await.unwrap());
+
+        list.revoke_token(1).await.unwrap();
+        list.revoke_subject("a", now).await.unwrap();
+        assert!(list.is_revoked(1, "b", issued).await.unwrap());
+        assert!(list.is_revoked(2, "a", issued).await.unwrap());
+        assert!(!list.is_revoked(2, "a", now).await.unwrap());
+        assert!(!list.is_revoked(2, "b", issued).await.unwrap());
+
+        // Other servers load revocations from the backend
+        let other = RevocationList::new()
+            .backend(FileRevocations::open(&path).await.unwrap())
+            .reload_interval(Duration::ZERO);
+        assert!(other.is_revoked(1, "b", issued).await.unwrap());
+        assert!(other.is_revoked(2, "a", issued).await.unwrap());
+
+        list.revoke_all(now).await.unwrap();
+        assert!(list.is_revoked(2, "b", issued).await.unwrap());
+        assert!(other.is_revoked(2, "b", issued).await.unwrap());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn revocation_pruning() {
+        let list = RevocationList::new().reload_interval(Duration::ZERO);
+        list.revoke(Revocation::Token {
+            nonce: 1,
+            expires: 0,
+        })
+        .await
+        .unwrap();
+        list.revoke(Revocation::All {
+            before: 0,
+            expires: 0,
+        })
+        .await
+        .unwrap();
+        assert!(!list.is_revoked(2, "a", Utc::now()).await.unwrap());
+        assert!(list.read().0.tokens.is_empty());
+        assert!(list.read().0.all.is_none());
+
+        // Expired ones are dropped on insert too
+        let list = RevocationList::new();
+        for nonce in 0..4 {
+            let expires = if nonce == 3 { i64::MAX } else { 0 };
+            list.revoke(Revocation::Token { nonce, expires })
+                .await
+                .unwrap();
+        }
+        assert_eq!(list.read().0.tokens.len(), 1);
+    }
+}
@@ -2,123 +2,762 @@
 //! A module that contains abstraction of stateless tokens
 //!
 
-use crate::encrypt::Aes;
+use crate::body::Body;
+use crate::encrypt::{Aes, Envelope, Kdf};
 use crate::error::Error;
+use crate::jwt::JwtConfig;
+use crate::proxy::ClientInfo;
 use crate::response::ResponseBuilder;
+use crate::route::{Error as RouteError, Route};
+use crate::token_store::{Refresh, RevocationList, Rotation, TokenStore};
+use arc_swap::ArcSwapOption;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use cookie::CookieBuilder;
-use headers::{Cookie, HeaderMapExt};
-use hyper::body::Incoming;
-use hyper::header::SET_COOKIE;
+use headers::authorization::Bearer;
+use headers::{Authorization, Cookie, HeaderMapExt};
+use http_body_util::{LengthLimitError, Limited};
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_TYPE, SET_COOKIE};
 use hyper::http::response::Builder;
-use hyper::Request;
-use lazy_static::lazy_static;
+use hyper::{Method, Request, Response, StatusCode};
 use rand::random;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::ops::Deref;
-use tokio::sync::RwLock;
+use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
-lazy_static! {
-    static ref CONFIG: RwLock<Option<TokenConfig>> = RwLock::new(None);
-}
+static CONFIG: ArcSwapOption<TokenConfig> = ArcSwapOption::const_empty();
 
 ///
 /// A struct that contains configuration for tokens
 ///
+/// Access tokens live 15 minutes and refresh tokens 90 days by default,
+/// tolerating 30 seconds of clock skew between servers.
+///
+/// Keys are given as [SecretKey]; Strings are taken as high-entropy secrets.
+/// Access and refresh tokens are encrypted with distinct subkeys derived from them.
+///
+/// # Key rotation
+///
+/// Tokens carry ID of the key they're encrypted with. New tokens are encrypted
+/// with the primary key, while tokens encrypted with other accepted keys are
+/// re-issued by [Session::from_request], so keys are rotated without logging users out.
+///
+/// ```
+/// use humus_terra::tokens::TokenConfig;
+///
+/// // Tokens of the old key are accepted and re-issued with key "2024-10"
+/// let config = TokenConfig::new("old secret", true).primary_key("2024-10", "new secret");
+///
+/// // Once sessions moved on, old key is removed
+/// let config = config.remove_key("");
+/// ```
+///
 pub struct TokenConfig {
-    key: String,
+    keys: Vec<TokenKey>,
     secure_cookie: bool,
+    access_lifetime: Duration,
+    refresh_lifetime: Duration,
+    clock_skew: Duration,
+    audience: String,
+    jwt: Option<JwtConfig>,
+    source: TokenSource,
+    store: Option<Arc<dyn TokenStore>>,
+    revocations: Option<RevocationList>,
 }
 
 impl TokenConfig {
     ///
-    /// Create new configuration for tokens
+    /// Create new configuration for tokens with primary key whose ID is empty
     ///
-    pub fn new(key: &str, secure_cookie: bool) -> Self {
+    /// # Migration
+    ///
+    /// Since 0.1, keys are derived from `key` and tokens are encoded in a new format,
+    /// so tokens issued by earlier versions are rejected and users are logged out once.
+    /// A string passed as `key` is derived with HKDF, which suits random secrets only;
+    /// Derive human-chosen secrets like `"secret"` with [SecretKey::passphrase] instead.
+    ///
+    pub fn new(key: impl Into<SecretKey>, secure_cookie: bool) -> Self {
         Self {
-            key: key.to_string(),
+            keys: vec![TokenKey::new("", key)],
             secure_cookie,
+            access_lifetime: Duration::from_secs(15 * 60),
+            refresh_lifetime: Duration::from_secs(90 * 24 * 60 * 60),
+            clock_skew: Duration::from_secs(30),
+            audience: String::new(),
+            jwt: None,
+            source: TokenSource::Cookie,
+            store: None,
+            revocations: None,
         }
     }
 
+    ///
+    /// Set primary key which new tokens are encrypted with.
+    /// Former primary key stays accepted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is longer than 255 bytes,
+    /// or contains characters other than ASCII alphanumerics, `-` and `_`.
+    ///
+    pub fn primary_key(mut self, id: &str, key: impl Into<SecretKey>) -> Self {
+        self.keys.retain(|k| k.id != id);
+        self.keys.insert(0, TokenKey::new(id, key));
+        self
+    }
+
+    ///
+    /// Accept tokens encrypted with given key, which are re-issued with primary key
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is longer than 255 bytes,
+    /// or contains characters other than ASCII alphanumerics, `-` and `_`.
+    ///
+    pub fn accept_key(mut self, id: &str, key: impl Into<SecretKey>) -> Self {
+        if self.keys[0].id == id {
+            return self;
+        }
+        self.keys.retain(|k| k.id != id);
+        self.keys.push(TokenKey::new(id, key));
+        self
+    }
+
+    ///
+    /// Stop accepting tokens encrypted with key of given ID. Primary key is never removed
+    ///
+    pub fn remove_key(mut self, id: &str) -> Self {
+        let primary = self.keys.remove(0);
+        self.keys.retain(|k| k.id != id);
+        self.keys.insert(0, primary);
+        self
+    }
+
+    ///
+    /// Set how long access tokens live until they're rotated
+    ///
+    pub fn access_lifetime(mut self, lifetime: Duration) -> Self {
+        self.access_lifetime = lifetime;
+        self
+    }
+
+    ///
+    /// Set how long refresh tokens live; Sessions idle longer than this expire
+    ///
+    pub fn refresh_lifetime(mut self, lifetime: Duration) -> Self {
+        self.refresh_lifetime = lifetime;
+        self
+    }
+
+    ///
+    /// Set tolerance for clocks of servers issuing and verifying tokens to disagree
+    ///
+    pub fn clock_skew(mut self, skew: Duration) -> Self {
+        self.clock_skew = skew;
+        self
+    }
+
+    ///
+    /// Set name of service which tokens are minted for.
+    ///
+    /// Tokens are accepted only by services of same audience,
+    /// even if services share keys.
+    ///
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = audience.to_string();
+        self
+    }
+
+    ///
+    /// Issue tokens as signed JWTs instead of encrypted format. See [crate::jwt].
+    ///
+    /// Audience is carried as `aud` claim. Tokens of encrypted format are
+    /// still accepted with keys of this configuration, and re-issued as JWTs.
+    ///
+    pub fn jwt(mut self, jwt: JwtConfig) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    ///
+    /// Set where [Session::from_request] reads tokens from; Cookies by default
+    ///
+    pub fn source(mut self, source: TokenSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    ///
+    /// Track refresh tokens in given store, which detects reuse of them
+    /// and enables [Session::logout] and [Session::logout_everywhere].
+    /// See [crate::token_store].
+    ///
+    pub fn store(mut self, store: impl TokenStore + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    ///
+    /// Reject tokens revoked in given list, both access and refresh ones.
+    /// Keep a clone of the list to revoke tokens.
+    ///
+    pub fn revocations(mut self, revocations: RevocationList) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
     ///
     /// Override configuration by given configuration
     ///
+    /// This never blocks, so it's safe to call from asynchronous contexts.
+    ///
     pub fn set(config: Self) {
-        *CONFIG.blocking_write() = Some(config);
+        CONFIG.store(Some(Arc::new(config)));
+    }
+
+    fn current() -> Result<Arc<Self>, TokenError> {
+        CONFIG.load_full().ok_or(TokenError::NotConfigured)
+    }
+
+    // Check token isn't issued later than now beyond clock skew
+    fn check_issued(&self, timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Result<(), TokenError> {
+        let ahead = timestamp.signed_duration_since(now).to_std();
+        match ahead.is_ok_and(|ahead| ahead > self.clock_skew) {
+            true => Err(TokenError::NotYetValid),
+            false => Ok(()),
+        }
+    }
+
+    async fn check_revoked<C>(&self, token: &Token<C>) -> Result<(), TokenError> {
+        let Some(revocations) = &self.revocations else {
+            return Ok(());
+        };
+        let issued = DateTime::from_timestamp(token.timestamp, 0).ok_or(TokenError::Malformed)?;
+        match revocations
+            .is_revoked(token.nonce, &token.who, issued)
+            .await
+        {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(TokenError::Revoked),
+            Err(err) => Err(TokenError::Store(err)),
+        }
+    }
+
+    // Negative durations fail to convert, so tokens from the future never outlive
+    fn outlives(&self, timestamp: DateTime<Utc>, lifetime: Duration, now: DateTime<Utc>) -> bool {
+        now.signed_duration_since(timestamp)
+            .to_std()
+            .is_ok_and(|age| age > lifetime.saturating_add(self.clock_skew))
+    }
+
+    fn required_store(&self) -> Result<&Arc<dyn TokenStore>, TokenError> {
+        self.store
+            .as_ref()
+            .ok_or_else(|| TokenError::Store(Box::new(Error::new("Token store not configured"))))
+    }
+
+    // When session refreshed at given time expires unless refreshed again
+    fn expiry(&self, now: i64) -> i64 {
+        let lifetime = i64::try_from(self.refresh_lifetime.as_secs()).unwrap_or(i64::MAX);
+        now.saturating_add(lifetime)
+            .saturating_add(self.clock_skew.as_secs() as i64)
+    }
+
+    fn primary(&self) -> &TokenKey {
+        &self.keys[0]
+    }
+
+    fn key(&self, id: &str) -> Option<&TokenKey> {
+        self.keys.iter().find(|k| k.id == id)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Token {
+///
+/// A secret key which keys of tokens are derived from
+///
+/// Strings convert with [SecretKey::from_secret], which doesn't stretch them;
+/// Use [SecretKey::passphrase] for human-chosen secrets.
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::tokens::{SecretKey, TokenConfig};
+///
+/// let key = SecretKey::passphrase("correct horse battery staple", b"my-app-salt").unwrap();
+/// let config = TokenConfig::new(key, true);
+/// ```
+///
+pub struct SecretKey {
+    prk: [u8; 32],
+}
+
+impl SecretKey {
+    ///
+    /// Use uniformly random 32 bytes as key as-is
+    ///
+    pub fn raw(key: [u8; 32]) -> Self {
+        Self { prk: key }
+    }
+
+    ///
+    /// Derive key from high-entropy secret like randomly generated string, with HKDF
+    ///
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            prk: Kdf::extract(secret),
+        }
+    }
+
+    ///
+    /// Derive key from low-entropy passphrase with Argon2id, which is deliberately slow.
+    ///
+    /// `salt` must be at least 8 bytes and same across servers sharing the key.
+    ///
+    pub fn passphrase(passphrase: &str, salt: &[u8]) -> Result<Self, TokenError> {
+        Ok(Self {
+            prk: Kdf::stretch(passphrase.as_bytes(), salt).map_err(TokenError::KeyDerivation)?,
+        })
+    }
+}
+
+impl From<&str> for SecretKey {
+    fn from(secret: &str) -> Self {
+        Self::from_secret(secret.as_bytes())
+    }
+}
+
+impl From<[u8; 32]> for SecretKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self::raw(key)
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Purpose {
+    Access,
+    Refresh,
+}
+
+impl Purpose {
+    // Associated data binding token to its type, key and audience
+    fn aad(self, key_id: &str, audience: &str) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(17 + key_id.len() + audience.len());
+        aad.push(match self {
+            Purpose::Access => b'A',
+            Purpose::Refresh => b'R',
+        });
+        for field in [key_id, audience] {
+            aad.extend_from_slice(&(field.len() as u64).to_be_bytes());
+            aad.extend_from_slice(field.as_bytes());
+        }
+        aad
+    }
+
+    // Type of JWT in `typ` header
+    fn typ(self) -> &'static str {
+        match self {
+            Purpose::Access => "at+jwt",
+            Purpose::Refresh => "rt+jwt",
+        }
+    }
+
+    fn lifetime(self, config: &TokenConfig) -> Duration {
+        match self {
+            Purpose::Access => config.access_lifetime,
+            Purpose::Refresh => config.refresh_lifetime,
+        }
+    }
+}
+
+struct TokenKey {
+    id: String,
+    access: Aes,
+    refresh: Aes,
+}
+
+impl TokenKey {
+    fn new(id: &str, key: impl Into<SecretKey>) -> Self {
+        assert!(
+            id.len() <= 255
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "Invalid token key ID: {}",
+            id
+        );
+
+        let key = key.into();
+        Self {
+            id: id.to_string(),
+            access: Aes::new(&Kdf::expand(&key.prk, b"humus-terra access token")),
+            refresh: Aes::new(&Kdf::expand(&key.prk, b"humus-terra refresh token")),
+        }
+    }
+
+    fn cipher(&self, purpose: Purpose) -> &Aes {
+        match purpose {
+            Purpose::Access => &self.access,
+            Purpose::Refresh => &self.refresh,
+        }
+    }
+}
+
+///
+/// An error of token validation
+///
+#[derive(Debug)]
+pub enum TokenError {
+    ///
+    /// Token system is not configured with [TokenConfig::set]
+    ///
+    NotConfigured,
+    ///
+    /// Request doesn't carry token
+    ///
+    Missing,
+    ///
+    /// Token is not well-formed
+    ///
+    Malformed,
+    ///
+    /// Token is not issued by this server or was modified
+    ///
+    Tampered,
+    ///
+    /// Token is issued later than now, beyond tolerated clock skew
+    ///
+    NotYetValid,
+    ///
+    /// Token lived longer than its lifetime
+    ///
+    Expired,
+    ///
+    /// Refresh token was used again after it's rotated
+    ///
+    Reused,
+    ///
+    /// Session of token was logged out, or token is revoked
+    ///
+    Revoked,
+    ///
+    /// Failed to encrypt token
+    ///
+    Encrypt(Error),
+    ///
+    /// Failed to derive key
+    ///
+    KeyDerivation(Error),
+    ///
+    /// Token is too large to fit in cookie
+    ///
+    TooLarge,
+    ///
+    /// Key is not valid for its algorithm, or can't sign
+    ///
+    InvalidKey,
+    ///
+    /// Claims can't be represented in token format
+    ///
+    InvalidClaims,
+    ///
+    /// Token store failed, or is not configured
+    ///
+    Store(Box<crate::token_store::Error>),
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::NotConfigured => write!(f, "Token system not configured"),
+            TokenError::Missing => write!(f, "Missing token"),
+            TokenError::Malformed => write!(f, "Malformed token"),
+            TokenError::Tampered => write!(f, "Token tampered"),
+            TokenError::NotYetValid => write!(f, "Token not yet valid"),
+            TokenError::Expired => write!(f, "Token expired"),
+            TokenError::Reused => write!(f, "Refresh token reused"),
+            TokenError::Revoked => write!(f, "Token revoked"),
+            TokenError::Encrypt(err) => write!(f, "Failed to encrypt token: {}", err),
+            TokenError::KeyDerivation(err) => write!(f, "Failed to derive key: {}", err),
+            TokenError::TooLarge => write!(f, "Token too large"),
+            TokenError::InvalidKey => write!(f, "Invalid key"),
+            TokenError::InvalidClaims => write!(f, "Invalid claims"),
+            TokenError::Store(err) => write!(f, "Token store error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+///
+/// A trait for custom claims carried in tokens, like roles of user
+///
+/// Implemented for all types which serde serializes and deserializes;
+/// User-defined structs, or maps like `serde_json::Map<String, serde_json::Value>`.
+/// Claims are encrypted, but make cookies larger, so keep them small.
+///
+pub trait Claims: Serialize + DeserializeOwned + Clone + Send + Sync {}
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + Sync> Claims for T {}
+
+// Cookie name and attributes share 4096 bytes with token
+static MAX_TOKEN_LENGTH: usize = 3968;
+
+#[derive(Debug, Clone)]
+struct Token<C> {
     who: String,
     timestamp: i64,
     nonce: i64,
+    // ID of session, shared by tokens rotated from same login
+    family: i64,
+    claims: C,
+    // Whether it's encrypted with key other than primary one
+    rekey: bool,
+    // Whether it's JWT past its own `exp`; Only stale access cookies get so far
+    stale: bool,
 }
 
 ///
 /// A struct that represents stateless access-token
 ///
-pub struct AccessToken {
-    inner: Token,
+pub struct AccessToken<C = ()> {
+    inner: Token<C>,
 }
 
 ///
 /// A struct that represents stateless refresh-token
 ///
-pub struct RefreshToken {
-    inner: Token,
+pub struct RefreshToken<C = ()> {
+    inner: Token<C>,
 }
 
-impl Token {
-    fn new(who: &str, timestamp: i64) -> Token {
+impl<C: Claims> Token<C> {
+    fn new(who: &str, timestamp: i64, family: i64, claims: C) -> Self {
         Self {
             who: who.to_string(),
             timestamp,
             nonce: random(),
+            family,
+            claims,
+            rekey: false,
+            stale: false,
         }
     }
 
-    fn from(encrypted: &str) -> Result<Token, Error> {
-        let config = CONFIG.blocking_read();
-        let key = match config.deref() {
-            None => return Err(Error::from("Token system not configured")),
-            Some(config) => config.key.as_ref(),
+    // Stale JWTs are accepted unless `enforce_expiry`, so that caller rotates them
+    fn from(encrypted: &str, purpose: Purpose, enforce_expiry: bool) -> Result<Self, TokenError> {
+        let config = TokenConfig::current()?;
+
+        // Envelopes never contain '.'
+        if let Some(jwt) = config.jwt.as_ref().filter(|_| encrypted.contains('.')) {
+            return Self::from_jwt(jwt, &config, encrypted, purpose, enforce_expiry);
+        }
+
+        let (id, encrypted) = Envelope::decode(encrypted).map_err(|_| TokenError::Malformed)?;
+        let key = config.key(&id).ok_or(TokenError::Tampered)?;
+        let decrypted = key
+            .cipher(purpose)
+            .decrypt(&encrypted, &purpose.aad(&id, &config.audience))
+            .map_err(|_| TokenError::Tampered)?;
+
+        let mut token = Self::decode(&decrypted).ok_or(TokenError::Malformed)?;
+        token.rekey = key.id != config.primary().id || config.jwt.is_some();
+
+        Ok(token)
+    }
+
+    fn from_jwt(
+        jwt: &JwtConfig,
+        config: &TokenConfig,
+        encoded: &str,
+        purpose: Purpose,
+        enforce_expiry: bool,
+    ) -> Result<Self, TokenError> {
+        let (mut claims, rekey) = jwt.decode(
+            encoded,
+            purpose.typ(),
+            &config.audience,
+            config.clock_skew,
+            enforce_expiry,
+        )?;
+        let stale = claims
+            .get("exp")
+            .and_then(Value::as_f64)
+            .is_some_and(|exp| {
+                Utc::now().timestamp() as f64 > exp + config.clock_skew.as_secs_f64()
+            });
+
+        let who = match claims.remove("sub") {
+            Some(Value::String(who)) => who,
+            _ => return Err(TokenError::Malformed),
         };
+        let timestamp = claims
+            .remove("iat")
+            .and_then(|iat| iat.as_i64())
+            .filter(|iat| DateTime::from_timestamp(*iat, 0).is_some())
+            .ok_or(TokenError::Malformed)?;
+        let nonce = claims
+            .remove("jti")
+            .and_then(|jti| jti.as_str()?.parse().ok())
+            .ok_or(TokenError::Malformed)?;
+        let family = claims
+            .remove("sid")
+            .and_then(|sid| sid.as_str()?.parse().ok())
+            .ok_or(TokenError::Malformed)?;
+        for registered in ["iss", "aud", "exp", "nbf"] {
+            claims.remove(registered);
+        }
 
-        let decrypted = Aes::decrypt(encrypted, key)?;
+        // Unit-like claims are omitted
+        let claims = match claims.is_empty() {
+            true => serde_json::from_value(Value::Null)
+                .or_else(|_| serde_json::from_value(Value::Object(claims))),
+            false => serde_json::from_value(Value::Object(claims)),
+        }
+        .map_err(|_| TokenError::Malformed)?;
 
-        serde_json::from_str::<Token>(&decrypted).map_err(Error::from)
+        Ok(Self {
+            who,
+            timestamp,
+            nonce,
+            family,
+            claims,
+            rekey,
+            stale,
+        })
     }
 
-    fn to_string(&self) -> Result<String, Error> {
-        let config = CONFIG.blocking_read();
-        let key = match config.deref() {
-            None => return Err(Error::from("Token system not configured")),
-            Some(config) => config.key.as_ref(),
+    fn to_jwt(
+        &self,
+        jwt: &JwtConfig,
+        config: &TokenConfig,
+        purpose: Purpose,
+    ) -> Result<String, TokenError> {
+        let mut claims = match serde_json::to_value(&self.claims) {
+            Ok(Value::Object(claims)) => claims,
+            Ok(Value::Null) => Map::new(),
+            _ => return Err(TokenError::InvalidClaims),
         };
 
-        let json = serde_json::to_string(self).unwrap();
+        let registered = ["iss", "sub", "aud", "exp", "nbf", "iat", "jti", "sid"];
+        if registered.iter().any(|name| claims.contains_key(*name)) {
+            return Err(TokenError::InvalidClaims);
+        }
 
-        Aes::encrypt(&json, key)
+        let lifetime = i64::try_from(purpose.lifetime(config).as_secs()).unwrap_or(i64::MAX);
+        claims.insert("sub".to_string(), Value::from(self.who.as_str()));
+        if !config.audience.is_empty() {
+            claims.insert("aud".to_string(), Value::from(config.audience.as_str()));
+        }
+        claims.insert(
+            "exp".to_string(),
+            Value::from(self.timestamp.saturating_add(lifetime)),
+        );
+        claims.insert("nbf".to_string(), Value::from(self.timestamp));
+        claims.insert("iat".to_string(), Value::from(self.timestamp));
+        claims.insert("jti".to_string(), Value::from(self.nonce.to_string()));
+        claims.insert("sid".to_string(), Value::from(self.family.to_string()));
+
+        jwt.encode(purpose.typ(), claims)
+    }
+
+    fn to_string(&self, purpose: Purpose) -> Result<String, TokenError> {
+        let config = TokenConfig::current()?;
+
+        if let Some(jwt) = &config.jwt {
+            let encoded = self.to_jwt(jwt, &config, purpose)?;
+            if encoded.len() > MAX_TOKEN_LENGTH {
+                return Err(TokenError::TooLarge);
+            }
+            return Ok(encoded);
+        }
+
+        if self.who.len() > u16::MAX as usize {
+            return Err(TokenError::TooLarge);
+        }
+
+        let key = config.primary();
+        let encrypted = key
+            .cipher(purpose)
+            .encrypt(&self.encode()?, &purpose.aad(&key.id, &config.audience))
+            .map_err(TokenError::Encrypt)?;
+
+        let encoded = Envelope::encode(&key.id, &encrypted);
+        if encoded.len() > MAX_TOKEN_LENGTH {
+            return Err(TokenError::TooLarge);
+        }
+
+        Ok(encoded)
+    }
+
+    // timestamp (8) | nonce (8) | family (8) | length of who (2) | who | claims in JSON
+    fn encode(&self) -> Result<Vec<u8>, TokenError> {
+        let claims = serde_json::to_vec(&self.claims)
+            .map_err(|err| TokenError::Encrypt(Error::from(err)))?;
+
+        let mut data = Vec::with_capacity(26 + self.who.len() + claims.len());
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.nonce.to_be_bytes());
+        data.extend_from_slice(&self.family.to_be_bytes());
+        data.extend_from_slice(&(self.who.len() as u16).to_be_bytes());
+        data.extend_from_slice(self.who.as_bytes());
+        data.extend_from_slice(&claims);
+        Ok(data)
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let timestamp = i64::from_be_bytes(data.get(0..8)?.try_into().ok()?);
+        let nonce = i64::from_be_bytes(data.get(8..16)?.try_into().ok()?);
+        let family = i64::from_be_bytes(data.get(16..24)?.try_into().ok()?);
+        let length = u16::from_be_bytes(data.get(24..26)?.try_into().ok()?) as usize;
+        let who = data.get(26..26 + length)?;
+        let claims = data.get(26 + length..)?;
+        // Reject timestamps out of range
+        DateTime::from_timestamp(timestamp, 0)?;
+
+        Some(Token {
+            who: String::from_utf8(who.to_vec()).ok()?,
+            timestamp,
+            nonce,
+            family,
+            claims: serde_json::from_slice(claims).ok()?,
+            rekey: false,
+            stale: false,
+        })
     }
 }
 
-impl AccessToken {
-    fn new(who: &str, timestamp: i64) -> Self {
+impl<C: Claims> AccessToken<C> {
+    fn new(who: &str, timestamp: i64, family: i64, claims: C) -> Self {
         Self {
-            inner: Token::new(who, timestamp),
+            inner: Token::new(who, timestamp, family, claims),
         }
     }
 
-    fn from(encrypted: &str) -> Result<Self, Error> {
+    fn from(encrypted: &str) -> Result<Self, TokenError> {
         Ok(Self {
-            inner: Token::from(encrypted)?,
+            inner: Token::from(encrypted, Purpose::Access, true)?,
         })
     }
 
-    fn to_string(&self) -> Result<String, Error> {
-        self.inner.to_string()
+    // Accept access token past its lifetime, which is rotated with refresh token
+    fn from_stale(encrypted: &str) -> Result<Self, TokenError> {
+        Ok(Self {
+            inner: Token::from(encrypted, Purpose::Access, false)?,
+        })
+    }
+
+    fn to_string(&self) -> Result<String, TokenError> {
+        self.inner.to_string(Purpose::Access)
     }
 
     ///
@@ -134,23 +773,37 @@ impl AccessToken {
     pub fn timestamp(&self) -> DateTime<Utc> {
         DateTime::from_timestamp(self.inner.timestamp, 0).unwrap()
     }
+
+    ///
+    /// Get nonce, which is `jti` claim of JWT and identifies token in [RevocationList]
+    ///
+    pub fn nonce(&self) -> i64 {
+        self.inner.nonce
+    }
+
+    ///
+    /// Get custom claims
+    ///
+    pub fn claims(&self) -> &C {
+        &self.inner.claims
+    }
 }
 
-impl RefreshToken {
-    fn new(who: &str, timestamp: i64) -> Self {
+impl<C: Claims> RefreshToken<C> {
+    fn new(who: &str, timestamp: i64, family: i64, claims: C) -> Self {
         Self {
-            inner: Token::new(who, timestamp),
+            inner: Token::new(who, timestamp, family, claims),
         }
     }
 
-    fn from(encrypted: &str) -> Result<Self, Error> {
+    fn from(encrypted: &str) -> Result<Self, TokenError> {
         Ok(Self {
-            inner: Token::from(encrypted)?,
+            inner: Token::from(encrypted, Purpose::Refresh, true)?,
         })
     }
 
-    fn to_string(&self) -> Result<String, Error> {
-        self.inner.to_string()
+    fn to_string(&self) -> Result<String, TokenError> {
+        self.inner.to_string(Purpose::Refresh)
     }
 
     ///
@@ -166,119 +819,1126 @@ impl RefreshToken {
     pub fn timestamp(&self) -> DateTime<Utc> {
         DateTime::from_timestamp(self.inner.timestamp, 0).unwrap()
     }
+
+    ///
+    /// Get nonce, which is `jti` claim of JWT and identifies token in [RevocationList]
+    ///
+    pub fn nonce(&self) -> i64 {
+        self.inner.nonce
+    }
+
+    ///
+    /// Get custom claims
+    ///
+    pub fn claims(&self) -> &C {
+        &self.inner.claims
+    }
 }
 
 ///
 /// A struct that represents stateless session token
 ///
-pub struct Session {
-    access_token: AccessToken,
-    refresh_token: RefreshToken,
+/// Tokens carry custom claims of type `C`. See [Claims].
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::tokens::Session;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Clone, Serialize, Deserialize)]
+/// struct User {
+///     tenant: u64,
+///     roles: Vec<String>,
+/// }
+///
+/// let session = Session::with_claims("alice", User { tenant: 1, roles: vec![] });
+/// assert_eq!(session.access_token().claims().tenant, 1);
+///
+/// // Type of claims is given on retrieval
+/// // let session: Session<User> = Session::from_request(&request).await?;
+/// ```
+///
+pub struct Session<C = ()> {
+    access_token: AccessToken<C>,
+    refresh_token: Option<RefreshToken<C>>,
+    reissued: bool,
 }
 
 impl Session {
-    fn read_cookie(key: &str, request: &Request<Incoming>) -> Option<String> {
+    ///
+    /// Create new session
+    ///
+    pub fn new(who: &str) -> Self {
+        Self::with_claims(who, ())
+    }
+
+    ///
+    /// Expire session cookies on given response, without revoking any token.
+    /// See [Session::logout]
+    ///
+    pub fn clear_cookies(builder: Builder) -> Builder {
+        ["__HT_ACCESS_TOKEN", "__HT_REFRESH_TOKEN"]
+            .into_iter()
+            .fold(builder, |builder, name| {
+                builder.header(
+                    SET_COOKIE,
+                    CookieBuilder::new(name, "")
+                        .http_only(true)
+                        .max_age(cookie::time::Duration::ZERO)
+                        .to_string(),
+                )
+            })
+    }
+}
+
+impl<C: Claims> Session<C> {
+    fn read_cookie<B>(key: &str, request: &Request<B>) -> Option<String> {
         let cookie = request.headers().typed_get::<Cookie>()?;
         Some(cookie.get(key)?.to_string())
     }
 
     ///
-    /// Create new session
+    /// Create new session carrying given claims
     ///
-    pub fn new(who: &str) -> Self {
+    pub fn with_claims(who: &str, claims: C) -> Self {
         let timestamp = Utc::now().timestamp();
+        Self::issue(who, timestamp, random(), claims, false)
+    }
 
+    fn issue(who: &str, timestamp: i64, family: i64, claims: C, reissued: bool) -> Self {
         Self {
-            access_token: AccessToken::new(who, timestamp),
-            refresh_token: RefreshToken::new(who, timestamp),
+            access_token: AccessToken::new(who, timestamp, family, claims.clone()),
+            refresh_token: Some(RefreshToken::new(who, timestamp, family, claims)),
+            reissued,
         }
     }
 
     ///
     /// Retrieve session information from request
     ///
-    pub fn from_request(request: &Request<Incoming>) -> Result<Self, Error> {
-        let access_token_str = Self::read_cookie("__HT_ACCESS_TOKEN", request)
-            .ok_or(Error::from("Missing access token"))?;
-        let mut access_token = AccessToken::from(&access_token_str)?;
+    /// Tokens are read from where [TokenConfig::source] allows.
+    ///
+    /// With cookies, access token older than its lifetime, or encrypted with key
+    /// other than primary one, is re-issued together with refresh token.
+    /// Respond with [Session::to_response] to hand the new ones to client.
+    ///
+    /// With `Authorization: Bearer` header, only access token is carried, so it
+    /// expires after its lifetime. Clients renew it at [TokenRefresh] route.
+    ///
+    /// Tokens revoked in [TokenConfig::revocations] are rejected.
+    ///
+    pub async fn from_request<B>(request: &Request<B>) -> Result<Self, TokenError> {
+        let config = TokenConfig::current()?;
 
-        let refresh_token_str = Self::read_cookie("__HT_REFRESH_TOKEN", request)
-            .ok_or(Error::from("Missing refresh token"))?;
-        let mut refresh_token = RefreshToken::from(&refresh_token_str)?;
+        let bearer = request
+            .headers()
+            .typed_get::<Authorization<Bearer>>()
+            .filter(|_| config.source != TokenSource::Cookie);
+        match bearer {
+            Some(Authorization(bearer)) => Self::from_bearer(&config, bearer.token()).await,
+            None if config.source == TokenSource::Bearer => Err(TokenError::Missing),
+            None => Self::from_cookies(&config, request).await,
+        }
+    }
+
+    async fn from_bearer(config: &TokenConfig, token: &str) -> Result<Self, TokenError> {
+        let access_token = AccessToken::<C>::from(token)?;
 
         let now = Utc::now();
+        config.check_issued(access_token.timestamp(), now)?;
+        if config.outlives(access_token.timestamp(), config.access_lifetime, now) {
+            return Err(TokenError::Expired);
+        }
+        config.check_revoked(&access_token.inner).await?;
+
+        Ok(Self {
+            access_token,
+            refresh_token: None,
+            reissued: false,
+        })
+    }
+
+    async fn from_cookies<B>(
+        config: &TokenConfig,
+        request: &Request<B>,
+    ) -> Result<Self, TokenError> {
+        let access_token_str =
+            Self::read_cookie("__HT_ACCESS_TOKEN", request).ok_or(TokenError::Missing)?;
+        let access_token = AccessToken::<C>::from_stale(&access_token_str)?;
+
+        let refresh_token_str =
+            Self::read_cookie("__HT_REFRESH_TOKEN", request).ok_or(TokenError::Missing)?;
+        let refresh_token = RefreshToken::<C>::from(&refresh_token_str)?;
 
-        if access_token.who() != refresh_token.who() {
-            return Err(Error::from("Token owner mismatched"));
+        if access_token.who() != refresh_token.who()
+            || access_token.inner.family != refresh_token.inner.family
+        {
+            return Err(TokenError::Tampered);
         }
 
         // Access tokens are always generated after or at same time with Refresh token
         // If Refresh token's timestamp is later than Access token's one,
         // It may be client reuses refresh token after token refreshed
         if refresh_token.timestamp() > access_token.timestamp() {
-            return Err(Error::from("Refresh token reused"));
+            return Err(TokenError::Reused);
         }
 
-        if refresh_token
-            .timestamp()
-            .signed_duration_since(now)
-            .num_days()
-            > 90
-        {
-            return Err(Error::from("Refresh token expired"));
+        let now = Utc::now();
+        config.check_issued(access_token.timestamp(), now)?;
+
+        if config.outlives(refresh_token.timestamp(), config.refresh_lifetime, now) {
+            return Err(TokenError::Expired);
         }
+        config.check_revoked(&access_token.inner).await?;
+        config.check_revoked(&refresh_token.inner).await?;
 
-        if access_token
-            .timestamp()
-            .signed_duration_since(now)
-            .num_minutes()
-            > 15
-        {
-            let timestamp = now.timestamp();
-            access_token = AccessToken::new(access_token.who(), timestamp);
-            refresh_token = RefreshToken::new(access_token.who(), timestamp);
+        let reissue = config.outlives(access_token.timestamp(), config.access_lifetime, now)
+            || access_token.inner.stale
+            || access_token.inner.rekey
+            || refresh_token.inner.rekey;
+        if reissue {
+            return Self::rotate(config, refresh_token, now).await;
         }
 
         Ok(Self {
             access_token,
-            refresh_token,
+            refresh_token: Some(refresh_token),
+            reissued: false,
         })
     }
 
     ///
-    /// Apply session information to response
+    /// Issue new session from refresh token, which clients using
+    /// `Authorization: Bearer` header keep by themselves
     ///
-    pub fn to_response(&self) -> Result<Builder, Error> {
-        let secure = CONFIG.blocking_read().as_ref().unwrap().secure_cookie;
+    pub async fn refresh(refresh_token: &str) -> Result<Self, TokenError> {
+        let config = TokenConfig::current()?;
+        let refresh_token = RefreshToken::<C>::from(refresh_token)?;
 
-        Ok(ResponseBuilder::new()
-            .header(
-                SET_COOKIE,
-                CookieBuilder::new("__HT_ACCESS_TOKEN", self.access_token.to_string()?)
-                    .http_only(true)
-                    .secure(secure)
-                    .to_string(),
-            )
-            .header(
+        let now = Utc::now();
+        config.check_issued(refresh_token.timestamp(), now)?;
+        if config.outlives(refresh_token.timestamp(), config.refresh_lifetime, now) {
+            return Err(TokenError::Expired);
+        }
+        config.check_revoked(&refresh_token.inner).await?;
+
+        Self::rotate(&config, refresh_token, now).await
+    }
+
+    // Replace refresh token with new one in same family
+    async fn rotate(
+        config: &TokenConfig,
+        refresh_token: RefreshToken<C>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, TokenError> {
+        let Token {
+            who,
+            nonce,
+            timestamp,
+            family,
+            claims,
+            ..
+        } = refresh_token.inner;
+        let mut session = Self::issue(&who, now.timestamp(), family, claims, true);
+
+        if let Some(store) = &config.store {
+            let refresh = Refresh {
+                who: &who,
+                family,
+                token: nonce,
+                issued: timestamp,
+                next: session.refresh_token.as_ref().unwrap().inner.nonce,
+                expires: config.expiry(now.timestamp()),
+            };
+            match store.rotate(&refresh).await.map_err(TokenError::Store)? {
+                Rotation::Rotated => {}
+                Rotation::Concurrent { next } => {
+                    session.refresh_token.as_mut().unwrap().inner.nonce = next;
+                }
+                Rotation::Reused => return Err(TokenError::Reused),
+                Rotation::Revoked => return Err(TokenError::Revoked),
+            }
+        }
+
+        Ok(session)
+    }
+
+    ///
+    /// Log out of the session, which requires token store; Refresh tokens of the
+    /// session are revoked in token store, and returned response for given request
+    /// expires cookies.
+    ///
+    /// Access tokens are stateless, so they're valid until their lifetime ends.
+    /// Without token store, refresh tokens can't be revoked, so this fails;
+    /// Use [Session::clear_cookies] to only expire cookies.
+    ///
+    pub async fn logout<B>(&self, request: &Request<B>) -> Result<Builder, TokenError> {
+        let config = TokenConfig::current()?;
+        let expires = config.expiry(Utc::now().timestamp());
+        config
+            .required_store()?
+            .revoke(self.access_token.inner.family, expires)
+            .await
+            .map_err(TokenError::Store)?;
+
+        Ok(Session::clear_cookies(ResponseBuilder::from_request(
+            request,
+        )))
+    }
+
+    ///
+    /// Log out of every session of who has the session, which requires token store.
+    /// Returned response for given request expires cookies.
+    ///
+    pub async fn logout_everywhere<B>(&self, request: &Request<B>) -> Result<Builder, TokenError> {
+        let config = TokenConfig::current()?;
+
+        // Sessions issued in this second are revoked too
+        let before = Utc::now().timestamp() + 1;
+        config
+            .required_store()?
+            .revoke_all(self.access_token.who(), before)
+            .await
+            .map_err(TokenError::Store)?;
+
+        Ok(Session::clear_cookies(ResponseBuilder::from_request(
+            request,
+        )))
+    }
+
+    ///
+    /// Apply session information to response for given request
+    ///
+    /// Cookies are marked as `Secure` if [TokenConfig] says so, or if the
+    /// original request was made over HTTPS, even if it was forwarded by
+    /// trusted proxies over plain HTTP. See [ClientInfo].
+    ///
+    pub fn to_response<B>(&self, request: &Request<B>) -> Result<Builder, TokenError> {
+        let secure = TokenConfig::current()?.secure_cookie
+            || ClientInfo::from_request(request).is_some_and(ClientInfo::is_secure);
+
+        let mut builder = ResponseBuilder::from_request(request).header(
+            SET_COOKIE,
+            CookieBuilder::new("__HT_ACCESS_TOKEN", self.access_token.to_string()?)
+                .http_only(true)
+                .secure(secure)
+                .to_string(),
+        );
+        if let Some(refresh_token) = &self.refresh_token {
+            builder = builder.header(
                 SET_COOKIE,
-                CookieBuilder::new("__HT_REFRESH_TOKEN", self.refresh_token.to_string()?)
+                CookieBuilder::new("__HT_REFRESH_TOKEN", refresh_token.to_string()?)
                     .http_only(true)
                     .secure(secure)
                     .to_string(),
-            ))
+            );
+        }
+
+        Ok(builder)
+    }
+
+    ///
+    /// Get tokens of the session in JSON for clients using `Authorization: Bearer` header
+    ///
+    /// ```json
+    /// {
+    ///     "access_token": "...",
+    ///     "refresh_token": "...",
+    ///     "token_type": "Bearer",
+    ///     "expires_in": 900
+    /// }
+    /// ```
+    ///
+    pub fn to_json(&self) -> Result<Value, TokenError> {
+        let config = TokenConfig::current()?;
+
+        let mut json = Map::new();
+        json.insert(
+            "access_token".to_string(),
+            Value::from(self.access_token.to_string()?),
+        );
+        if let Some(refresh_token) = &self.refresh_token {
+            json.insert(
+                "refresh_token".to_string(),
+                Value::from(refresh_token.to_string()?),
+            );
+        }
+        json.insert("token_type".to_string(), Value::from("Bearer"));
+        json.insert(
+            "expires_in".to_string(),
+            Value::from(config.access_lifetime.as_secs()),
+        );
+
+        Ok(Value::Object(json))
+    }
+
+    ///
+    /// Get whether tokens were re-issued by [Session::from_request] or [Session::refresh]
+    ///
+    pub fn is_reissued(&self) -> bool {
+        self.reissued
     }
 
     ///
     /// Get access token of the session
     ///
-    pub fn access_token(&self) -> &AccessToken {
+    pub fn access_token(&self) -> &AccessToken<C> {
         &self.access_token
     }
 
     ///
-    /// Get refresh token of the session
+    /// Get refresh token of the session;
+    /// `None` if the session is retrieved from `Authorization: Bearer` header
+    ///
+    pub fn refresh_token(&self) -> Option<&RefreshToken<C>> {
+        self.refresh_token.as_ref()
+    }
+}
+
+///
+/// A route that renews tokens for clients using `Authorization: Bearer` header
+///
+/// Clients `POST` refresh token in JSON body like `{"refresh_token": "..."}`,
+/// and receive new tokens as [Session::to_json]. Failures are answered with
+/// `400 Bad Request` and `{"error": "invalid_grant"}`.
+/// Bodies over 8 KiB are answered with `413 Payload Too Large`.
+///
+/// Refresh tokens carry claims of type `C`, which new tokens inherit.
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::tokens::TokenRefresh;
+///
+/// let route = TokenRefresh::<()>::new("refresh");
+/// ```
+///
+pub struct TokenRefresh<C = ()> {
+    name: String,
+    claims: PhantomData<fn() -> C>,
+}
+
+impl<C: Claims> TokenRefresh<C> {
+    ///
+    /// Create new route of given name
+    ///
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            claims: PhantomData,
+        }
+    }
+
+    fn respond<B>(request: &Request<B>, status: StatusCode, json: &Value) -> Response<Body> {
+        ResponseBuilder::from_request(request)
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CACHE_CONTROL, "no-store")
+            .body(Body::from(json.to_string()))
+            .unwrap()
+    }
+}
+
+// Refresh token is at most MAX_TOKEN_LENGTH, so larger requests are never valid
+static MAX_REFRESH_REQUEST_SIZE: usize = 8 * 1024;
+
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[async_trait]
+impl<C: Claims + 'static> Route for TokenRefresh<C> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Result<Response<Body>, Box<RouteError>> {
+        if request.method() != Method::POST {
+            return Ok(ResponseBuilder::from_request(&request)
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(ALLOW, "POST")
+                .body(Body::empty())?);
+        }
+
+        let (parts, body) = request.into_parts();
+        let request = Request::from_parts(parts, ());
+
+        let refresh_request = match Body::new(Limited::new(body, MAX_REFRESH_REQUEST_SIZE))
+            .to_bytes()
+            .await
+        {
+            Ok(body) => {
+                serde_json::from_slice::<RefreshRequest>(&body).map_err(|_| StatusCode::BAD_REQUEST)
+            }
+            Err(err) if err.downcast_ref::<LengthLimitError>().is_some() => {
+                Err(StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            Err(_) => Err(StatusCode::BAD_REQUEST),
+        };
+        let refresh_request = match refresh_request {
+            Ok(refresh_request) => refresh_request,
+            Err(status) => {
+                let error = serde_json::json!({ "error": "invalid_request" });
+                return Ok(Self::respond(&request, status, &error));
+            }
+        };
+
+        match Session::<C>::refresh(&refresh_request.refresh_token).await {
+            Ok(session) => Ok(Self::respond(&request, StatusCode::OK, &session.to_json()?)),
+            Err(err @ (TokenError::NotConfigured | TokenError::Store(_))) => Err(Box::new(err)),
+            Err(_) => {
+                let error = serde_json::json!({ "error": "invalid_grant" });
+                Ok(Self::respond(&request, StatusCode::BAD_REQUEST, &error))
+            }
+        }
+    }
+}
+
+///
+/// A source of tokens which [Session::from_request] reads
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenSource {
+    ///
+    /// Access and refresh tokens in cookies
+    ///
+    Cookie,
     ///
-    pub fn refresh_token(&self) -> &RefreshToken {
-        &self.refresh_token
+    /// Access token in `Authorization: Bearer` header
+    ///
+    Bearer,
+    ///
+    /// `Authorization: Bearer` header if request has it, or cookies otherwise
+    ///
+    Both,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::COOKIE;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config as ProptestConfig, TestRng, TestRunner};
+    use tokio::sync::{Mutex, MutexGuard};
+
+    // Tests share global configuration
+    static LOCK: Mutex<()> = Mutex::const_new(());
+
+    async fn configure(config: TokenConfig) -> MutexGuard<'static, ()> {
+        let guard = LOCK.lock().await;
+        TokenConfig::set(config);
+        guard
+    }
+
+    fn request<C: Claims>(
+        access_token: &AccessToken<C>,
+        refresh_token: &RefreshToken<C>,
+    ) -> Request<()> {
+        let cookie = format!(
+            "__HT_ACCESS_TOKEN={}; __HT_REFRESH_TOKEN={}",
+            access_token.to_string().unwrap(),
+            refresh_token.to_string().unwrap()
+        );
+        Request::builder().header(COOKIE, cookie).body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let _guard = configure(TokenConfig::new("secret", false)).await;
+        let now = Utc::now().timestamp();
+        let minutes = |minutes: i64| now - minutes * 60;
+
+        let fresh = request(
+            &AccessToken::new("a", now, 0, ()),
+            &RefreshToken::new("a", now, 0, ()),
+        );
+        let session = Session::<()>::from_request(&fresh).await.unwrap();
+        assert_eq!(session.access_token().timestamp().timestamp(), now);
+
+        // Stale access token is rotated while refresh token is alive
+        let stale = request(
+            &AccessToken::new("a", minutes(20), 0, ()),
+            &RefreshToken::new("a", minutes(20), 0, ()),
+        );
+        let session = Session::<()>::from_request(&stale).await.unwrap();
+        assert!(session.access_token().timestamp().timestamp() >= now);
+        assert!(session.refresh_token().unwrap().timestamp().timestamp() >= now);
+
+        let expired = request(
+            &AccessToken::new("a", minutes(91 * 24 * 60), 0, ()),
+            &RefreshToken::new("a", minutes(91 * 24 * 60), 0, ()),
+        );
+        assert!(matches!(
+            Session::<()>::from_request(&expired).await,
+            Err(TokenError::Expired)
+        ));
+
+        let future = request(
+            &AccessToken::new("a", minutes(-5), 0, ()),
+            &RefreshToken::new("a", minutes(-5), 0, ()),
+        );
+        assert!(matches!(
+            Session::<()>::from_request(&future).await,
+            Err(TokenError::NotYetValid)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tampered_and_reused() {
+        let _guard = configure(TokenConfig::new("secret", false)).await;
+        let now = Utc::now().timestamp();
+
+        let reused = request(
+            &AccessToken::new("a", now - 60, 0, ()),
+            &RefreshToken::new("a", now, 0, ()),
+        );
+        assert!(matches!(
+            Session::<()>::from_request(&reused).await,
+            Err(TokenError::Reused)
+        ));
+
+        let mismatched = request(
+            &AccessToken::new("a", now, 0, ()),
+            &RefreshToken::new("b", now, 0, ()),
+        );
+        assert!(matches!(
+            Session::<()>::from_request(&mismatched).await,
+            Err(TokenError::Tampered)
+        ));
+
+        let forged = Request::builder()
+            .header(COOKIE, "__HT_ACCESS_TOKEN=AAAA; __HT_REFRESH_TOKEN=AAAA")
+            .body(())
+            .unwrap();
+        assert!(matches!(
+            Session::<()>::from_request(&forged).await,
+            Err(TokenError::Malformed)
+        ));
+
+        let missing = Request::builder().body(()).unwrap();
+        assert!(matches!(
+            Session::<()>::from_request(&missing).await,
+            Err(TokenError::Missing)
+        ));
+    }
+
+    #[tokio::test]
+    async fn key_rotation() {
+        let _guard = configure(TokenConfig::new("old", false)).await;
+        let now = Utc::now().timestamp();
+        let old = request(
+            &AccessToken::new("a", now, 0, ()),
+            &RefreshToken::new("a", now, 0, ()),
+        );
+        assert!(!Session::<()>::from_request(&old)
+            .await
+            .unwrap()
+            .is_reissued());
+
+        TokenConfig::set(TokenConfig::new("old", false).primary_key("k1", "new"));
+        let session = Session::<()>::from_request(&old).await.unwrap();
+        assert!(session.is_reissued());
+        let access_token = session.access_token().to_string().unwrap();
+        let (id, encrypted) = Envelope::decode(&access_token).unwrap();
+        assert_eq!(id, "k1");
+
+        let new = request(session.access_token(), session.refresh_token().unwrap());
+        assert!(!Session::<()>::from_request(&new)
+            .await
+            .unwrap()
+            .is_reissued());
+
+        TokenConfig::set(
+            TokenConfig::new("old", false)
+                .primary_key("k1", "new")
+                .remove_key(""),
+        );
+        assert!(matches!(
+            Session::<()>::from_request(&old).await,
+            Err(TokenError::Tampered)
+        ));
+        assert!(Session::<()>::from_request(&new).await.is_ok());
+
+        // Key ID can't be swapped
+        let swapped = Envelope::encode("k2", &encrypted);
+        TokenConfig::set(
+            TokenConfig::new("old", false)
+                .primary_key("k1", "new")
+                .accept_key("k2", "other"),
+        );
+        assert!(matches!(
+            AccessToken::<()>::from(&swapped),
+            Err(TokenError::Tampered)
+        ));
+    }
+
+    #[tokio::test]
+    async fn key_derivation() {
+        let key = SecretKey::passphrase("passphrase", b"salt of app").unwrap();
+        assert!(SecretKey::passphrase("passphrase", b"salt").is_err());
+
+        let _guard = configure(TokenConfig::new(key, false)).await;
+        let token = AccessToken::new("a", Utc::now().timestamp(), 0, ());
+        let encrypted = token.to_string().unwrap();
+        assert_eq!(AccessToken::<()>::from(&encrypted).unwrap().who(), "a");
+
+        // Access and refresh tokens are encrypted with different subkeys
+        assert!(matches!(
+            RefreshToken::<()>::from(&encrypted),
+            Err(TokenError::Tampered)
+        ));
+
+        TokenConfig::set(TokenConfig::new([7u8; 32], false));
+        assert!(matches!(
+            AccessToken::<()>::from(&encrypted),
+            Err(TokenError::Tampered)
+        ));
+    }
+
+    // Seeded, so failures reproduce; Set PROPTEST_CASES to fuzz longer
+    fn fuzz_runner() -> TestRunner {
+        let config = ProptestConfig {
+            failure_persistence: None,
+            ..ProptestConfig::default()
+        };
+        let rng = TestRng::deterministic_rng(config.rng_algorithm);
+        TestRunner::new_with_rng(config, rng)
+    }
+
+    #[tokio::test]
+    async fn fuzz_harness() {
+        let _guard = configure(TokenConfig::new("secret", false).accept_key("k", "other")).await;
+        let valid = AccessToken::new("a", Utc::now().timestamp(), 0, ())
+            .to_string()
+            .unwrap();
+        let mutated = valid.clone();
+
+        // Any input is rejected with error instead of panic
+        let inputs = prop_oneof![
+            "[A-Za-z0-9_=+/.-]{0,160}",
+            (
+                prop::sample::select(vec!["", "k", "x"]),
+                vec(any::<u8>(), 0..96)
+            )
+                .prop_map(|(key_id, data)| Envelope::encode(key_id, &data)),
+            (
+                any::<prop::sample::Index>(),
+                "[A-Za-z0-9_=+/.-]",
+                any::<bool>()
+            )
+                .prop_map(move |(index, replacement, truncate)| {
+                    let index = index.index(mutated.len());
+                    match truncate {
+                        true => mutated[..index].to_string(),
+                        false => {
+                            format!(
+                                "{}{}{}",
+                                &mutated[..index],
+                                replacement,
+                                &mutated[index + 1..]
+                            )
+                        }
+                    }
+                }),
+        ];
+        fuzz_runner()
+            .run(&inputs, |input| {
+                let access_token = AccessToken::<()>::from(&input);
+                prop_assert!(access_token.is_err() || input == valid);
+                prop_assert!(RefreshToken::<serde_json::Value>::from(&input).is_err());
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(matches!(
+            AccessToken::<()>::from(&"A".repeat(5000)),
+            Err(TokenError::Malformed)
+        ));
+        assert!(Token::<()>::decode(&[0; 25]).is_none());
+
+        fuzz_runner()
+            .run(&vec(any::<u8>(), 0..128), |data| {
+                Token::<serde_json::Value>::decode(&data);
+                Ok(())
+            })
+            .unwrap();
+
+        // Whatever is encoded decodes back as it was; Timestamps are limited to years 1970-9999
+        let tokens = (
+            "\\PC{0,32}",
+            0..253_402_300_800i64,
+            any::<i64>(),
+            vec(any::<i32>(), 0..16),
+        );
+        fuzz_runner()
+            .run(&tokens, |(who, timestamp, family, claims)| {
+                let token = RefreshToken::new(&who, timestamp, family, claims.clone());
+                let decoded = RefreshToken::<Vec<i32>>::from(&token.to_string().unwrap()).unwrap();
+                prop_assert_eq!(decoded.who(), who.as_str());
+                prop_assert_eq!(decoded.inner.timestamp, timestamp);
+                prop_assert_eq!(decoded.inner.family, family);
+                prop_assert_eq!(decoded.nonce(), token.nonce());
+                prop_assert_eq!(decoded.claims(), &claims);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn associated_data() {
+        let _guard = configure(TokenConfig::new("secret", false).audience("api")).await;
+        let token = AccessToken::new("a", Utc::now().timestamp(), 0, ());
+        let encrypted = token.to_string().unwrap();
+        assert!(AccessToken::<()>::from(&encrypted).is_ok());
+
+        TokenConfig::set(TokenConfig::new("secret", false).audience("admin"));
+        assert!(matches!(
+            AccessToken::<()>::from(&encrypted),
+            Err(TokenError::Tampered)
+        ));
+
+        // Type is bound even if ciphers were shared
+        let config = TokenConfig::current().unwrap();
+        let sealed = config
+            .primary()
+            .access
+            .encrypt(
+                &token.inner.encode().unwrap(),
+                &Purpose::Refresh.aad("", "admin"),
+            )
+            .unwrap();
+        assert!(matches!(
+            AccessToken::<()>::from(&Envelope::encode("", &sealed)),
+            Err(TokenError::Tampered)
+        ));
+    }
+
+    #[tokio::test]
+    async fn claims() {
+        let _guard = configure(TokenConfig::new("secret", false)).await;
+        let mut claims = serde_json::Map::new();
+        claims.insert("tenant".to_string(), serde_json::json!(42));
+        claims.insert("roles".to_string(), serde_json::json!(["admin"]));
+
+        let session = Session::with_claims("a", claims.clone());
+        let request = request(session.access_token(), session.refresh_token().unwrap());
+        let session: Session<serde_json::Map<String, serde_json::Value>> =
+            Session::from_request(&request).await.unwrap();
+        assert_eq!(session.access_token().claims(), &claims);
+
+        // Claims of other type are rejected
+        assert!(matches!(
+            Session::<Vec<u8>>::from_request(&request).await,
+            Err(TokenError::Malformed)
+        ));
+
+        let large = Session::with_claims("a", "x".repeat(4096));
+        assert!(matches!(
+            large.to_response(&Request::new(())),
+            Err(TokenError::TooLarge)
+        ));
+    }
+
+    #[tokio::test]
+    async fn jwt() {
+        use crate::jwt::JwtKey;
+        use base64::Engine;
+
+        let _guard = configure(TokenConfig::new("secret", false).audience("api")).await;
+        let now = Utc::now().timestamp();
+        let encrypted = request(
+            &AccessToken::new("a", now, 0, ()),
+            &RefreshToken::new("a", now, 0, ()),
+        );
+
+        let jwt = JwtConfig::new(JwtKey::hs256("k", &[1; 32])).issuer("auth");
+        TokenConfig::set(TokenConfig::new("secret", false).audience("api").jwt(jwt));
+
+        // Encrypted tokens are migrated to JWT
+        let session = Session::<()>::from_request(&encrypted).await.unwrap();
+        assert!(session.is_reissued());
+        let access_token = session.access_token().to_string().unwrap();
+        let payload = access_token.split('.').nth(1).unwrap();
+        let payload: Value = serde_json::from_slice(
+            &base64::prelude::BASE64_URL_SAFE_NO_PAD
+                .decode(payload)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(payload["sub"], "a");
+        assert_eq!(payload["iss"], "auth");
+        assert_eq!(payload["aud"], "api");
+        assert_eq!(
+            payload["exp"].as_i64().unwrap() - payload["iat"].as_i64().unwrap(),
+            15 * 60
+        );
+
+        let fresh = request(session.access_token(), session.refresh_token().unwrap());
+        let session = Session::<()>::from_request(&fresh).await.unwrap();
+        assert!(!session.is_reissued());
+        assert_eq!(session.access_token().who(), "a");
+
+        // Access JWT past its `exp` is rotated in cookies, but rejected as bearer
+        let stale = AccessToken::new("a", now - 20 * 60, 0, ());
+        let stale_str = stale.to_string().unwrap();
+        assert!(matches!(
+            AccessToken::<()>::from(&stale_str),
+            Err(TokenError::Expired)
+        ));
+        let stale_refresh = RefreshToken::new("a", now - 20 * 60, 0, ());
+        let reissued = Session::<()>::from_request(&request(&stale, &stale_refresh))
+            .await
+            .unwrap();
+        assert!(reissued.is_reissued());
+
+        // Refresh token is not an access token
+        let refresh_token = session.refresh_token().unwrap().to_string().unwrap();
+        assert!(matches!(
+            AccessToken::<()>::from(&refresh_token),
+            Err(TokenError::Tampered)
+        ));
+
+        let mut claims = serde_json::Map::new();
+        claims.insert("roles".to_string(), serde_json::json!(["admin"]));
+        let session = Session::with_claims("a", claims.clone());
+        let access_token = session.access_token().to_string().unwrap();
+        let decoded = AccessToken::<Map<String, Value>>::from(&access_token).unwrap();
+        assert_eq!(decoded.claims(), &claims);
+
+        claims.insert("sub".to_string(), Value::from("b"));
+        let conflicting = Session::with_claims("a", claims);
+        assert!(matches!(
+            conflicting.access_token().to_string(),
+            Err(TokenError::InvalidClaims)
+        ));
+    }
+
+    #[tokio::test]
+    async fn bearer() {
+        use hyper::header::AUTHORIZATION;
+
+        let _guard = configure(TokenConfig::new("secret", false).source(TokenSource::Both)).await;
+        let now = Utc::now().timestamp();
+        let bearer = |token: &AccessToken| {
+            Request::builder()
+                .header(
+                    AUTHORIZATION,
+                    format!("Bearer {}", token.to_string().unwrap()),
+                )
+                .body(())
+                .unwrap()
+        };
+
+        let session = Session::<()>::from_request(&bearer(&AccessToken::new("a", now, 0, ())))
+            .await
+            .unwrap();
+        assert_eq!(session.access_token().who(), "a");
+        assert!(session.refresh_token().is_none());
+
+        // Bearer tokens aren't rotated without refresh token
+        let stale = bearer(&AccessToken::new("a", now - 20 * 60, 0, ()));
+        assert!(matches!(
+            Session::<()>::from_request(&stale).await,
+            Err(TokenError::Expired)
+        ));
+
+        let cookies = request(
+            &AccessToken::new("a", now, 0, ()),
+            &RefreshToken::new("a", now, 0, ()),
+        );
+        assert!(Session::<()>::from_request(&cookies).await.is_ok());
+
+        TokenConfig::set(TokenConfig::new("secret", false).source(TokenSource::Bearer));
+        assert!(matches!(
+            Session::<()>::from_request(&cookies).await,
+            Err(TokenError::Missing)
+        ));
+
+        // Refresh endpoint
+        let route = TokenRefresh::<()>::new("refresh");
+        let refresh_token = RefreshToken::new("a", now - 60 * 60, 0, ())
+            .to_string()
+            .unwrap();
+        let post = |body: String| {
+            Request::builder()
+                .method(Method::POST)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let body = serde_json::json!({ "refresh_token": refresh_token }).to_string();
+        let response = route.handle(post(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+        let json: Value =
+            serde_json::from_slice(&response.into_body().to_bytes().await.unwrap()).unwrap();
+        assert_eq!(json["token_type"], "Bearer");
+        assert_eq!(json["expires_in"], 900);
+
+        let access_token = json["access_token"].as_str().unwrap();
+        let renewed = Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
+            .body(())
+            .unwrap();
+        assert!(Session::<()>::from_request(&renewed).await.is_ok());
+
+        // Access token is not a refresh token
+        let body = serde_json::json!({ "refresh_token": access_token }).to_string();
+        let response = route.handle(post(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = route.handle(post("{}".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = serde_json::json!({ "refresh_token": "A".repeat(10000) }).to_string();
+        let response = route.handle(post(body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn store() {
+        use crate::response::ResponseConfig;
+        use crate::token_store::MemoryTokenStore;
+
+        let _guard = configure(TokenConfig::new("secret", false)).await;
+        let now = Utc::now().timestamp();
+        let session = Session::new("a");
+        assert!(matches!(
+            session.logout(&Request::new(())).await,
+            Err(TokenError::Store(_))
+        ));
+        assert!(matches!(
+            session.logout_everywhere(&Request::new(())).await,
+            Err(TokenError::Store(_))
+        ));
+
+        TokenConfig::set(TokenConfig::new("secret", false).store(MemoryTokenStore::new()));
+        let stale = request(
+            &AccessToken::new("a", now - 20 * 60, 1, ()),
+            &RefreshToken::new("a", now - 20 * 60, 1, ()),
+        );
+        // Concurrent requests with the same tokens get the same refresh token
+        let (first, second) = tokio::join!(
+            Session::<()>::from_request(&stale),
+            Session::<()>::from_request(&stale)
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(first.is_reissued() && second.is_reissued());
+        assert_eq!(
+            first.refresh_token().unwrap().inner.nonce,
+            second.refresh_token().unwrap().inner.nonce
+        );
+        let rotated = second.refresh_token().unwrap().to_string().unwrap();
+        let session = Session::<()>::refresh(&rotated).await.unwrap();
+
+        // Replaying token replaced before revokes whole family
+        assert!(matches!(
+            Session::<()>::from_request(&stale).await,
+            Err(TokenError::Reused)
+        ));
+        let rotated = session.refresh_token().unwrap().to_string().unwrap();
+        assert!(matches!(
+            Session::<()>::refresh(&rotated).await,
+            Err(TokenError::Revoked)
+        ));
+
+        let mismatched = request(
+            &AccessToken::new("a", now, 2, ()),
+            &RefreshToken::new("a", now, 3, ()),
+        );
+        assert!(matches!(
+            Session::<()>::from_request(&mismatched).await,
+            Err(TokenError::Tampered)
+        ));
+
+        let refresh_token = |who: &str, timestamp: i64, family: i64| {
+            RefreshToken::new(who, timestamp, family, ())
+                .to_string()
+                .unwrap()
+        };
+        let session = Session::<()>::refresh(&refresh_token("b", now - 60, 4))
+            .await
+            .unwrap();
+        let mut response_config = ResponseConfig::new();
+        response_config.access_control_allow_origin = Some("https://example.com".to_string());
+        let mut logout = Request::new(());
+        logout.extensions_mut().insert(Arc::new(response_config));
+        let response = session.logout(&logout).await.unwrap().body(()).unwrap();
+        assert_eq!(
+            response.headers()[hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        let cookies = response.headers().get_all(SET_COOKIE).iter();
+        assert!(cookies
+            .map(|cookie| cookie.to_str().unwrap())
+            .all(|cookie| cookie.contains("Max-Age=0")));
+        let rotated = session.refresh_token().unwrap().to_string().unwrap();
+        assert!(matches!(
+            Session::<()>::refresh(&rotated).await,
+            Err(TokenError::Revoked)
+        ));
+
+        let session = Session::<()>::refresh(&refresh_token("c", now - 60, 5))
+            .await
+            .unwrap();
+        session.logout_everywhere(&Request::new(())).await.unwrap();
+        assert!(matches!(
+            Session::<()>::refresh(&refresh_token("c", now - 30, 6)).await,
+            Err(TokenError::Revoked)
+        ));
+        assert!(Session::<()>::refresh(&refresh_token("d", now - 30, 7))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn revocations() {
+        let revocations = RevocationList::new();
+        let _guard =
+            configure(TokenConfig::new("secret", false).revocations(revocations.clone())).await;
+        let now = Utc::now().timestamp();
+
+        let session = Session::new("a");
+        let cookies = request(session.access_token(), session.refresh_token().unwrap());
+        assert!(Session::<()>::from_request(&cookies).await.is_ok());
+        revocations
+            .revoke_token(session.access_token().nonce())
+            .await
+            .unwrap();
+        assert!(matches!(
+            Session::<()>::from_request(&cookies).await,
+            Err(TokenError::Revoked)
+        ));
+
+        // Revoked refresh token can't be exchanged either
+        let stale = request(
+            &AccessToken::new("b", now - 20 * 60, 1, ()),
+            &RefreshToken::new("b", now - 20 * 60, 1, ()),
+        );
+        revocations
+            .revoke_subject("b", DateTime::from_timestamp(now - 60, 0).unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            Session::<()>::from_request(&stale).await,
+            Err(TokenError::Revoked)
+        ));
+        let renewed = request(
+            &AccessToken::new("b", now, 2, ()),
+            &RefreshToken::new("b", now, 2, ()),
+        );
+        assert!(Session::<()>::from_request(&renewed).await.is_ok());
+
+        revocations
+            .revoke_all(Utc::now() + chrono::Duration::days(1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            Session::<()>::from_request(&renewed).await,
+            Err(TokenError::Revoked)
+        ));
     }
 }
@@ -0,0 +1,1262 @@
+//!
+//! A module that provides [WebSocket](https://www.rfc-editor.org/rfc/rfc6455) for routes
+//!
+//! Routes accept WebSocket with [WebSocketUpgrade], which handles both
+//! HTTP/1.1 `Upgrade` and HTTP/2 extended `CONNECT` ([RFC 8441](https://www.rfc-editor.org/rfc/rfc8441)).
+//! Once the handshake completes, the connection is handed to a callback as [WebSocket]
+//! that sends and receives [Message].
+//!
+//! Messages are compressed with [permessage-deflate](https://www.rfc-editor.org/rfc/rfc7692)
+//! if client offers it, and frames and messages exceeding limits of [WebSocketConfig]
+//! close the connection with `1009 Message Too Big`.
+//!
+//! # Examples
+//!
+//! ```
+//! use humus_terra::body::Body;
+//! use humus_terra::websocket::{Message, WebSocketUpgrade};
+//! use hyper::{Request, Response};
+//!
+//! fn echo(mut request: Request<Body>) -> Response<Body> {
+//!     let upgrade = match WebSocketUpgrade::from_request(&mut request) {
+//!         Ok(upgrade) => upgrade,
+//!         Err(error) => return error.into_response(&request),
+//!     };
+//!
+//!     upgrade.on_upgrade(|mut socket| async move {
+//!         while let Some(Ok(message)) = socket.recv().await {
+//!             if let Message::Text(_) | Message::Binary(_) = message {
+//!                 if socket.send(message).await.is_err() {
+//!                     break;
+//!                 }
+//!             }
+//!         }
+//!     })
+//! }
+//! ```
+//!
+
+use crate::body::Body;
+use crate::compression::NoCompression;
+use crate::response::{ResponseBuilder, ResponseConfig};
+use base64::Engine;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use hyper::body::Bytes;
+use hyper::ext::Protocol;
+use hyper::header::{
+    HeaderMap, HeaderName, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Method, Request, Response, StatusCode, Version};
+use hyper_util::rt::TokioIo;
+use sha1::{Digest, Sha1};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+static GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+static DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+///
+/// A configuration of WebSocket connections
+///
+/// # Examples
+///
+/// ```
+/// use humus_terra::websocket::WebSocketConfig;
+///
+/// let config = WebSocketConfig::new()
+///     .max_frame_size(64 * 1024)
+///     .max_message_size(1024 * 1024)
+///     .permessage_deflate(false);
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    max_frame_size: usize,
+    max_message_size: usize,
+    permessage_deflate: bool,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketConfig {
+    ///
+    /// Create new configuration that limits frames to 16 MiB and messages to 64 MiB,
+    /// accepting permessage-deflate
+    ///
+    pub fn new() -> Self {
+        Self {
+            max_frame_size: 16 * 1024 * 1024,
+            max_message_size: 64 * 1024 * 1024,
+            permessage_deflate: true,
+        }
+    }
+
+    ///
+    /// Set maximum size of payload of received frames
+    ///
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    ///
+    /// Set maximum size of received messages, after decompression
+    ///
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    ///
+    /// Set whether to accept permessage-deflate offered by clients
+    ///
+    pub fn permessage_deflate(mut self, enabled: bool) -> Self {
+        self.permessage_deflate = enabled;
+        self
+    }
+}
+
+///
+/// A message of WebSocket
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// UTF-8 text message
+    Text(String),
+    /// Binary message
+    Binary(Bytes),
+    /// Ping; Received pings are answered with pong automatically
+    Ping(Bytes),
+    /// Pong
+    Pong(Bytes),
+    /// Close with optional code and reason
+    Close(Option<CloseFrame>),
+}
+
+///
+/// A code and reason of closing WebSocket
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    /// Status code; See [RFC 6455](https://www.rfc-editor.org/rfc/rfc6455#section-7.4)
+    pub code: u16,
+    /// Reason of closing
+    pub reason: String,
+}
+
+///
+/// An error of WebSocket connections
+///
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// Underlying connection failed
+    Io(io::Error),
+    /// Peer violated the protocol
+    Protocol(&'static str),
+    /// Received frame exceeded [WebSocketConfig::max_frame_size]
+    FrameTooLarge,
+    /// Received message exceeded [WebSocketConfig::max_message_size]
+    MessageTooLarge,
+    /// Received text message or close reason wasn't UTF-8
+    InvalidUtf8,
+    /// Connection is closed already
+    Closed,
+}
+
+impl WebSocketError {
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(1002),
+            WebSocketError::InvalidUtf8 => Some(1007),
+            WebSocketError::FrameTooLarge | WebSocketError::MessageTooLarge => Some(1009),
+            WebSocketError::Io(_) | WebSocketError::Closed => None,
+        }
+    }
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketError::Io(error) => write!(f, "WebSocket I/O error: {}", error),
+            WebSocketError::Protocol(message) => write!(f, "WebSocket protocol error: {}", message),
+            WebSocketError::FrameTooLarge => write!(f, "WebSocket frame too large"),
+            WebSocketError::MessageTooLarge => write!(f, "WebSocket message too large"),
+            WebSocketError::InvalidUtf8 => write!(f, "WebSocket text is not UTF-8"),
+            WebSocketError::Closed => write!(f, "WebSocket is closed"),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {}
+
+impl From<io::Error> for WebSocketError {
+    fn from(error: io::Error) -> Self {
+        WebSocketError::Io(error)
+    }
+}
+
+///
+/// An error of WebSocket handshake
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpgradeError {
+    /// Request doesn't ask for WebSocket
+    NotWebSocket,
+    /// Client speaks version of WebSocket other than 13
+    UnsupportedVersion,
+    /// `Sec-WebSocket-Key` is missing or malformed
+    InvalidKey,
+}
+
+impl UpgradeError {
+    ///
+    /// Create response for failed handshake with configuration which applies to given request;
+    /// `426 Upgrade Required` for unsupported version, `400 Bad Request` otherwise
+    ///
+    pub fn into_response<B>(self, request: &Request<B>) -> Response<Body> {
+        let builder = ResponseBuilder::from_request(request);
+        let builder = match self {
+            UpgradeError::UnsupportedVersion => builder
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .header(SEC_WEBSOCKET_VERSION, "13"),
+            _ => builder.status(StatusCode::BAD_REQUEST),
+        };
+
+        builder.body(Body::empty()).unwrap()
+    }
+}
+
+impl Display for UpgradeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpgradeError::NotWebSocket => write!(f, "request is not a WebSocket handshake"),
+            UpgradeError::UnsupportedVersion => write!(f, "unsupported WebSocket version"),
+            UpgradeError::InvalidKey => write!(f, "invalid Sec-WebSocket-Key"),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeError {}
+
+///
+/// Negotiated parameters of permessage-deflate
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    ///
+    /// Accept the first offer of permessage-deflate that can be honoured
+    ///
+    fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let offers = headers
+            .get_all(SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        offers.filter_map(Self::parse_offer).next()
+    }
+
+    fn parse_offer(offer: &str) -> Option<Self> {
+        let mut parts = offer.split(';').map(str::trim);
+        if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") {
+            return None;
+        }
+
+        let mut params = Self::default();
+        let mut seen = vec![];
+        for part in parts {
+            let (name, value) = match part.split_once('=') {
+                None => (part, None),
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            };
+            let name = name.to_ascii_lowercase();
+            if seen.contains(&name) {
+                return None;
+            }
+
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                // Window of compressor can't be narrowed
+                ("server_max_window_bits", Some("15")) => {}
+                // Window of decompressor is the widest already
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits))
+                    if bits
+                        .parse::<u8>()
+                        .is_ok_and(|bits| (8..=15).contains(&bits)) => {}
+                _ => return None,
+            }
+            seen.push(name);
+        }
+
+        Some(params)
+    }
+
+    fn header_value(&self) -> String {
+        let mut value = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        value
+    }
+}
+
+///
+/// Compute `Sec-WebSocket-Accept` for given `Sec-WebSocket-Key`
+///
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::prelude::BASE64_STANDARD.encode(sha1.finalize())
+}
+
+fn header_contains(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+///
+/// A pending WebSocket handshake
+///
+pub struct WebSocketUpgrade {
+    on_upgrade: OnUpgrade,
+    // `None` for HTTP/2, which doesn't use keys
+    key: Option<String>,
+    offered_protocols: Vec<String>,
+    protocols: Vec<String>,
+    deflate: Option<DeflateParams>,
+    config: WebSocketConfig,
+    response_config: Option<Arc<ResponseConfig>>,
+}
+
+impl WebSocketUpgrade {
+    ///
+    /// Start handshake of given request;
+    /// `GET` with `Upgrade: websocket` over HTTP/1.1 or `CONNECT` with `:protocol` of `websocket` over HTTP/2
+    ///
+    pub fn from_request<B>(request: &mut Request<B>) -> Result<Self, UpgradeError> {
+        let headers = request.headers();
+
+        let is_http2 = request.method() == Method::CONNECT
+            && request.version() == Version::HTTP_2
+            && request
+                .extensions()
+                .get::<Protocol>()
+                .is_some_and(|protocol| protocol.as_str().eq_ignore_ascii_case("websocket"));
+        let is_http1 = request.method() == Method::GET
+            && request.version() == Version::HTTP_11
+            && header_contains(headers, CONNECTION, "upgrade")
+            && header_contains(headers, UPGRADE, "websocket");
+        if !is_http1 && !is_http2 {
+            return Err(UpgradeError::NotWebSocket);
+        }
+
+        if headers
+            .get(SEC_WEBSOCKET_VERSION)
+            .is_none_or(|version| version != "13")
+        {
+            return Err(UpgradeError::UnsupportedVersion);
+        }
+
+        let key = match is_http1 {
+            false => None,
+            true => {
+                let key = headers
+                    .get(SEC_WEBSOCKET_KEY)
+                    .and_then(|key| key.to_str().ok())
+                    .ok_or(UpgradeError::InvalidKey)?;
+                let decoded = base64::prelude::BASE64_STANDARD
+                    .decode(key)
+                    .map_err(|_| UpgradeError::InvalidKey)?;
+                if decoded.len() != 16 {
+                    return Err(UpgradeError::InvalidKey);
+                }
+                Some(key.to_string())
+            }
+        };
+
+        let offered_protocols = headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim().to_string())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        let deflate = DeflateParams::negotiate(headers);
+        let response_config = request.extensions().get::<Arc<ResponseConfig>>().cloned();
+
+        Ok(Self {
+            on_upgrade: hyper::upgrade::on(request),
+            key,
+            offered_protocols,
+            protocols: vec![],
+            deflate,
+            config: WebSocketConfig::new(),
+            response_config,
+        })
+    }
+
+    ///
+    /// Set configuration of connection
+    ///
+    pub fn config(mut self, config: WebSocketConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    ///
+    /// Set subprotocols supported by server;
+    /// The first one client offers is selected
+    ///
+    pub fn protocols(mut self, protocols: &[&str]) -> Self {
+        self.protocols = protocols
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect();
+        self
+    }
+
+    ///
+    /// Complete handshake, calling `callback` with connection once it's upgraded.
+    /// Returns response that must be sent to client to complete handshake
+    ///
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response<Body>
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let protocol = self
+            .offered_protocols
+            .iter()
+            .find(|protocol| self.protocols.contains(protocol))
+            .cloned();
+        let deflate = self.deflate.filter(|_| self.config.permessage_deflate);
+
+        let mut builder = match &self.response_config {
+            None => ResponseBuilder::new(),
+            Some(config) => ResponseBuilder::with_config(config),
+        };
+        builder = match &self.key {
+            None => builder.status(StatusCode::OK),
+            Some(key) => builder
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "upgrade")
+                .header(UPGRADE, "websocket")
+                .header(SEC_WEBSOCKET_ACCEPT, accept_key(key)),
+        };
+        if let Some(protocol) = &protocol {
+            builder = builder.header(SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        if let Some(deflate) = &deflate {
+            builder = builder.header(SEC_WEBSOCKET_EXTENSIONS, deflate.header_value());
+        }
+
+        let config = self.config;
+        let on_upgrade = self.on_upgrade;
+        tokio::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let socket = WebSocket::new(TokioIo::new(upgraded), config, deflate, protocol);
+                    callback(socket).await
+                }
+                Err(err) => {
+                    crate::log!(warn "WebSocket upgrade failed: {}", err);
+                }
+            }
+        });
+
+        let mut response = builder.body(Body::empty()).unwrap();
+        response.extensions_mut().insert(NoCompression);
+        response
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+///
+/// A message being reassembled from fragments
+///
+struct Fragments {
+    opcode: u8,
+    compressed: bool,
+    payload: Vec<u8>,
+}
+
+struct Deflate {
+    params: DeflateParams,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+///
+/// An established WebSocket connection
+///
+/// [WebSocket::recv] is cancel-safe, so it can be used in `tokio::select!`.
+/// If it's cancelled while replying to Ping or Close, the next call completes
+/// the reply before returning the message.
+///
+pub struct WebSocket {
+    io: Box<dyn Io>,
+    config: WebSocketConfig,
+    protocol: Option<String>,
+    deflate: Option<Deflate>,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    fragments: Option<Fragments>,
+    // Control message whose reply isn't flushed yet
+    pending: Option<Message>,
+    close_sent: bool,
+    closed: bool,
+}
+
+impl WebSocket {
+    fn new<T>(
+        io: T,
+        config: WebSocketConfig,
+        deflate: Option<DeflateParams>,
+        protocol: Option<String>,
+    ) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            io: Box::new(io),
+            config,
+            protocol,
+            deflate: deflate.map(|params| Deflate {
+                params,
+                compress: Compress::new(Compression::default(), false),
+                decompress: Decompress::new(false),
+            }),
+            read_buffer: vec![],
+            write_buffer: vec![],
+            fragments: None,
+            pending: None,
+            close_sent: false,
+            closed: false,
+        }
+    }
+
+    ///
+    /// Get subprotocol selected during handshake
+    ///
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    ///
+    /// Receive next message. Returns `None` once connection is closed
+    ///
+    pub async fn recv(&mut self) -> Option<Result<Message, WebSocketError>> {
+        if self.closed {
+            return None;
+        }
+
+        match self.read_message().await {
+            Ok(message) => Some(Ok(message)),
+            Err(error) => {
+                self.closed = true;
+                if let Some(code) = error.close_code().filter(|_| !self.close_sent) {
+                    self.queue_frame(OP_CLOSE, false, &code.to_be_bytes());
+                    self.close_sent = true;
+                    let _ = self.flush().await;
+                }
+                Some(Err(error))
+            }
+        }
+    }
+
+    ///
+    /// Send message
+    ///
+    pub async fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+
+        match message {
+            Message::Text(text) => self.queue_data(OP_TEXT, text.as_bytes()),
+            Message::Binary(bytes) => self.queue_data(OP_BINARY, &bytes),
+            Message::Ping(bytes) | Message::Pong(bytes) if bytes.len() > 125 => {
+                return Err(WebSocketError::Protocol("control frame payload too large"))
+            }
+            Message::Ping(bytes) => self.queue_frame(OP_PING, false, &bytes),
+            Message::Pong(bytes) => self.queue_frame(OP_PONG, false, &bytes),
+            Message::Close(frame) => {
+                let mut payload = vec![];
+                if let Some(frame) = frame {
+                    payload.extend_from_slice(&frame.code.to_be_bytes());
+                    payload.extend_from_slice(frame.reason.as_bytes());
+                }
+                if payload.len() > 125 {
+                    return Err(WebSocketError::Protocol("close reason too long"));
+                }
+
+                self.queue_frame(OP_CLOSE, false, &payload);
+                self.close_sent = true;
+            }
+        }
+
+        self.flush().await
+    }
+
+    ///
+    /// Start closing handshake with given code and reason.
+    /// Keep calling [WebSocket::recv] until it returns `None` to complete it
+    ///
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string(),
+        };
+        self.send(Message::Close(Some(frame))).await
+    }
+
+    async fn flush(&mut self) -> Result<(), WebSocketError> {
+        while !self.write_buffer.is_empty() {
+            let written = self.io.write(&self.write_buffer).await?;
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            self.write_buffer.drain(..written);
+        }
+
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    // Control messages are handed over after their replies are flushed;
+    // If flushing is cancelled, both are kept for next call
+    async fn flush_pending(&mut self) -> Result<Option<Message>, WebSocketError> {
+        self.flush().await?;
+        if matches!(self.pending, Some(Message::Close(_))) {
+            let _ = self.io.shutdown().await;
+            self.closed = true;
+        }
+        Ok(self.pending.take())
+    }
+
+    async fn read_message(&mut self) -> Result<Message, WebSocketError> {
+        if let Some(message) = self.flush_pending().await? {
+            return Ok(message);
+        }
+
+        loop {
+            let frame = match self.parse_frame()? {
+                Some(frame) => frame,
+                None => {
+                    if self.io.read_buf(&mut self.read_buffer).await? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    continue;
+                }
+            };
+
+            match frame.opcode {
+                OP_PING => {
+                    self.queue_frame(OP_PONG, false, &frame.payload);
+                    self.pending = Some(Message::Ping(Bytes::from(frame.payload)));
+                }
+                OP_PONG => return Ok(Message::Pong(Bytes::from(frame.payload))),
+                OP_CLOSE => {
+                    let close = parse_close(&frame.payload)?;
+                    if !self.close_sent {
+                        let code = close.as_ref().map(|close| close.code.to_be_bytes());
+                        self.queue_frame(OP_CLOSE, false, code.as_ref().map_or(&[], |c| &c[..]));
+                        self.close_sent = true;
+                    }
+                    self.pending = Some(Message::Close(close));
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.fragments.is_some() {
+                        return Err(WebSocketError::Protocol(
+                            "message interleaved with fragments",
+                        ));
+                    }
+                    if frame.rsv1 && self.deflate.is_none() {
+                        return Err(WebSocketError::Protocol("compression was not negotiated"));
+                    }
+                    if frame.payload.len() > self.config.max_message_size {
+                        return Err(WebSocketError::MessageTooLarge);
+                    }
+
+                    self.fragments = Some(Fragments {
+                        opcode: frame.opcode,
+                        compressed: frame.rsv1,
+                        payload: frame.payload,
+                    });
+                }
+                _ => {
+                    let fragments = self
+                        .fragments
+                        .as_mut()
+                        .ok_or(WebSocketError::Protocol("unexpected continuation frame"))?;
+                    if frame.rsv1 {
+                        return Err(WebSocketError::Protocol("continuation frame with RSV1"));
+                    }
+                    if fragments.payload.len() + frame.payload.len() > self.config.max_message_size
+                    {
+                        return Err(WebSocketError::MessageTooLarge);
+                    }
+
+                    fragments.payload.extend_from_slice(&frame.payload);
+                }
+            }
+
+            if self.pending.is_some() {
+                return Ok(self.flush_pending().await?.unwrap());
+            }
+
+            if frame.fin {
+                let fragments = self.fragments.take().unwrap();
+                return self.complete(fragments);
+            }
+        }
+    }
+
+    ///
+    /// Parse frame at the front of read buffer; Returns `None` if it's incomplete
+    ///
+    fn parse_frame(&mut self) -> Result<Option<Frame>, WebSocketError> {
+        let buffer = &self.read_buffer;
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buffer[0] & 0x80 != 0;
+        let rsv1 = buffer[0] & 0x40 != 0;
+        let opcode = buffer[0] & 0x0F;
+        if buffer[0] & 0x30 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits are set"));
+        }
+        if !matches!(
+            opcode,
+            OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+        ) {
+            return Err(WebSocketError::Protocol("unknown opcode"));
+        }
+        if buffer[1] & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+
+        let (length, offset) = match buffer[1] & 0x7F {
+            126 if buffer.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+            127 if buffer.len() < 10 => return Ok(None),
+            127 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+            length => (length as u64, 2),
+        };
+
+        if opcode >= OP_CLOSE && (!fin || rsv1 || length > 125) {
+            return Err(WebSocketError::Protocol("malformed control frame"));
+        }
+        if length > self.config.max_frame_size as u64 {
+            return Err(WebSocketError::FrameTooLarge);
+        }
+
+        let length = length as usize;
+        if buffer.len() < offset + 4 + length {
+            return Ok(None);
+        }
+
+        let mask = [
+            buffer[offset],
+            buffer[offset + 1],
+            buffer[offset + 2],
+            buffer[offset + 3],
+        ];
+        let payload = buffer[offset + 4..offset + 4 + length]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        self.read_buffer.drain(..offset + 4 + length);
+
+        Ok(Some(Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        }))
+    }
+
+    fn complete(&mut self, fragments: Fragments) -> Result<Message, WebSocketError> {
+        let payload = match (fragments.compressed, self.deflate.as_mut()) {
+            (true, Some(deflate)) => {
+                Self::inflate(deflate, fragments.payload, self.config.max_message_size)?
+            }
+            _ => fragments.payload,
+        };
+
+        match fragments.opcode {
+            OP_TEXT => String::from_utf8(payload)
+                .map(Message::Text)
+                .map_err(|_| WebSocketError::InvalidUtf8),
+            _ => Ok(Message::Binary(Bytes::from(payload))),
+        }
+    }
+
+    fn inflate(
+        deflate: &mut Deflate,
+        mut payload: Vec<u8>,
+        max_message_size: usize,
+    ) -> Result<Vec<u8>, WebSocketError> {
+        payload.extend_from_slice(&DEFLATE_TAIL);
+
+        let mut output = Vec::with_capacity(payload.len() * 2);
+        let mut input = &payload[..];
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().max(1024));
+            }
+
+            let total_in = deflate.decompress.total_in();
+            let total_out = output.len();
+            deflate
+                .decompress
+                .decompress_vec(input, &mut output, FlushDecompress::Sync)
+                .map_err(|_| WebSocketError::Protocol("malformed compressed message"))?;
+            let consumed = (deflate.decompress.total_in() - total_in) as usize;
+            input = &input[consumed..];
+
+            if output.len() > max_message_size {
+                return Err(WebSocketError::MessageTooLarge);
+            }
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            if consumed == 0 && output.len() == total_out {
+                return Err(WebSocketError::Protocol("malformed compressed message"));
+            }
+        }
+
+        if deflate.params.client_no_context_takeover {
+            deflate.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+
+    fn deflate(deflate: &mut Deflate, mut input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().max(1024));
+            }
+
+            let total_in = deflate.compress.total_in();
+            deflate
+                .compress
+                .compress_vec(input, &mut output, FlushCompress::Sync)
+                .expect("deflate never fails on valid state");
+            input = &input[(deflate.compress.total_in() - total_in) as usize..];
+
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TAIL) {
+            output.truncate(output.len() - DEFLATE_TAIL.len());
+        }
+        if deflate.params.server_no_context_takeover {
+            deflate.compress.reset();
+        }
+
+        output
+    }
+
+    fn queue_data(&mut self, opcode: u8, payload: &[u8]) {
+        match self.deflate.as_mut() {
+            None => self.queue_frame(opcode, false, payload),
+            Some(deflate) => {
+                let compressed = Self::deflate(deflate, payload);
+                self.queue_frame(opcode, true, &compressed)
+            }
+        }
+    }
+
+    fn queue_frame(&mut self, opcode: u8, rsv1: bool, payload: &[u8]) {
+        let buffer = &mut self.write_buffer;
+        buffer.push(0x80 | if rsv1 { 0x40 } else { 0 } | opcode);
+        match payload.len() {
+            length @ 0..=125 => buffer.push(length as u8),
+            length @ 126..=0xFFFF => {
+                buffer.push(126);
+                buffer.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                buffer.push(127);
+                buffer.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        buffer.extend_from_slice(payload);
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+    match payload.len() {
+        0 => return Ok(None),
+        1 => return Err(WebSocketError::Protocol("malformed close frame")),
+        _ => {}
+    }
+
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+        return Err(WebSocketError::Protocol("invalid close code"));
+    }
+
+    let reason =
+        String::from_utf8(payload[2..].to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?;
+    Ok(Some(CloseFrame { code, reason }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    fn client_frame(opcode: u8, fin: bool, rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | (if rsv1 { 0x40 } else { 0 }) | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    async fn server_frame(client: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await.unwrap();
+        let length = match header[1] {
+            126 => client.read_u16().await.unwrap() as usize,
+            length => length as usize,
+        };
+
+        let mut payload = vec![0u8; length];
+        client.read_exact(&mut payload).await.unwrap();
+        (header[0], payload)
+    }
+
+    fn connect(
+        config: WebSocketConfig,
+        deflate: Option<DeflateParams>,
+    ) -> (WebSocket, DuplexStream) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        (WebSocket::new(server, config, deflate, None), client)
+    }
+
+    #[test]
+    fn handshake_key() {
+        // Example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn negotiate_deflate() {
+        let negotiate = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(SEC_WEBSOCKET_EXTENSIONS, value.parse().unwrap());
+            DeflateParams::negotiate(&headers)
+        };
+
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits"),
+            Some(DeflateParams::default())
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover")
+                .map(|params| params.header_value()),
+            Some("permessage-deflate; server_no_context_takeover".to_string())
+        );
+        assert_eq!(negotiate("permessage-deflate; unknown"), None);
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+    }
+
+    #[tokio::test]
+    async fn upgrade() {
+        let mut request = Request::builder()
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(SEC_WEBSOCKET_PROTOCOL, "v2, v1")
+            .header(SEC_WEBSOCKET_EXTENSIONS, "permessage-deflate")
+            .body(())
+            .unwrap();
+        let response = WebSocketUpgrade::from_request(&mut request)
+            .unwrap()
+            .protocols(&["v1", "v2"])
+            .on_upgrade(|_| async {});
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            response.headers()[SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(response.headers()[SEC_WEBSOCKET_PROTOCOL], "v2");
+        assert_eq!(
+            response.headers()[SEC_WEBSOCKET_EXTENSIONS],
+            "permessage-deflate"
+        );
+
+        let mut request = Request::builder()
+            .method(Method::CONNECT)
+            .version(Version::HTTP_2)
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .extension(Protocol::from_static("websocket"))
+            .body(())
+            .unwrap();
+        let response = WebSocketUpgrade::from_request(&mut request)
+            .unwrap()
+            .config(WebSocketConfig::new().permessage_deflate(false))
+            .on_upgrade(|_| async {});
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(SEC_WEBSOCKET_ACCEPT));
+
+        let mut request = Request::builder()
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "8")
+            .body(())
+            .unwrap();
+        let error = WebSocketUpgrade::from_request(&mut request).err().unwrap();
+        assert_eq!(error, UpgradeError::UnsupportedVersion);
+        assert_eq!(
+            error.into_response(&request).status(),
+            StatusCode::UPGRADE_REQUIRED
+        );
+
+        let mut request = Request::builder().body(()).unwrap();
+        let error = WebSocketUpgrade::from_request(&mut request).err().unwrap();
+        assert_eq!(error, UpgradeError::NotWebSocket);
+    }
+
+    #[tokio::test]
+    async fn messages() {
+        let (mut socket, mut client) = connect(WebSocketConfig::new(), None);
+
+        client
+            .write_all(&client_frame(OP_TEXT, true, false, b"hello"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OP_BINARY, false, false, &[1, 2]))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OP_PING, true, false, b"p"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OP_CONTINUATION, true, false, &[3]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            socket.recv().await.unwrap().unwrap(),
+            Message::Text("hello".to_string())
+        );
+        // Control frames may be interleaved with fragments
+        assert_eq!(
+            socket.recv().await.unwrap().unwrap(),
+            Message::Ping(Bytes::from_static(b"p"))
+        );
+        assert_eq!(
+            server_frame(&mut client).await,
+            (0x80 | OP_PONG, b"p".to_vec())
+        );
+        assert_eq!(
+            socket.recv().await.unwrap().unwrap(),
+            Message::Binary(Bytes::from_static(&[1, 2, 3]))
+        );
+
+        socket
+            .send(Message::Text("world".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut client).await,
+            (0x80 | OP_TEXT, b"world".to_vec())
+        );
+
+        let mut close = 1000u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        client
+            .write_all(&client_frame(OP_CLOSE, true, false, &close))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.recv().await.unwrap().unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: 1000,
+                reason: "bye".to_string()
+            }))
+        );
+        assert_eq!(
+            server_frame(&mut client).await,
+            (0x80 | OP_CLOSE, vec![0x03, 0xE8])
+        );
+        assert!(socket.recv().await.is_none());
+        assert!(matches!(
+            socket.send(Message::Text("late".to_string())).await,
+            Err(WebSocketError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let config = WebSocketConfig::new().max_frame_size(4);
+        let (mut socket, mut client) = connect(config, None);
+
+        client
+            .write_all(&client_frame(OP_TEXT, true, false, b"hello"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            socket.recv().await,
+            Some(Err(WebSocketError::FrameTooLarge))
+        ));
+        assert_eq!(
+            server_frame(&mut client).await,
+            (0x80 | OP_CLOSE, vec![0x03, 0xF1])
+        );
+        assert!(socket.recv().await.is_none());
+
+        let (mut socket, mut client) = connect(WebSocketConfig::new(), None);
+        let unmasked = [0x81, 0x01, b'a'];
+        client.write_all(&unmasked).await.unwrap();
+        assert!(matches!(
+            socket.recv().await,
+            Some(Err(WebSocketError::Protocol(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn permessage_deflate() {
+        let params = DeflateParams::default();
+        let (mut socket, mut client) = connect(WebSocketConfig::new(), Some(params));
+
+        let text = "compressible ".repeat(64);
+        let mut client_deflate = Deflate {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        };
+
+        // Context is taken over across messages
+        for _ in 0..2 {
+            let compressed = WebSocket::deflate(&mut client_deflate, text.as_bytes());
+            assert!(compressed.len() < text.len());
+            client
+                .write_all(&client_frame(OP_TEXT, true, true, &compressed))
+                .await
+                .unwrap();
+            assert_eq!(
+                socket.recv().await.unwrap().unwrap(),
+                Message::Text(text.clone())
+            );
+        }
+
+        socket.send(Message::Text(text.clone())).await.unwrap();
+        let (header, payload) = server_frame(&mut client).await;
+        assert_eq!(header, 0x80 | 0x40 | OP_TEXT);
+
+        let inflated = WebSocket::inflate(&mut client_deflate, payload, usize::MAX).unwrap();
+        assert_eq!(String::from_utf8(inflated).unwrap(), text);
+
+        // Bombs are stopped by message size limit
+        let config = WebSocketConfig::new().max_message_size(1024);
+        let (mut socket, mut client) = connect(config, Some(params));
+        let mut client_deflate = Deflate {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        };
+        let bomb = WebSocket::deflate(&mut client_deflate, &[0u8; 64 * 1024]);
+        client
+            .write_all(&client_frame(OP_BINARY, true, true, &bomb))
+            .await
+            .unwrap();
+        assert!(matches!(
+            socket.recv().await,
+            Some(Err(WebSocketError::MessageTooLarge))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancelled_reply() {
+        // Small buffer blocks writing pong until client reads it
+        let (mut client, server) = tokio::io::duplex(16);
+        let mut socket = WebSocket::new(server, WebSocketConfig::default(), None, None);
+
+        let ping = client_frame(OP_PING, true, false, &[7; 50]);
+        let (written, received) = tokio::join!(
+            client.write_all(&ping),
+            tokio::time::timeout(std::time::Duration::from_millis(50), socket.recv())
+        );
+        written.unwrap();
+        assert!(received.is_err());
+
+        let (pong, received) = tokio::join!(server_frame(&mut client), socket.recv());
+        assert_eq!(pong, (0x80 | OP_PONG, vec![7; 50]));
+        assert_eq!(
+            received.unwrap().unwrap(),
+            Message::Ping(Bytes::from(vec![7; 50]))
+        );
+    }
+}